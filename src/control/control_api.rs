@@ -1,42 +1,197 @@
-use actix_web::{get, post, web, HttpResponse, Responder};
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, Responder};
+use std::collections::HashSet;
+use std::str::FromStr;
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
 
-use crate::core::core_types::NewTaskInfo;
-use crate::models::tasks::{CreateTaskDefinitionResponse, TaskDefinitionModel, TaskStateModel};
-use crate::registry::task_registry::TaskRegistry;
+use crate::core::core_types::{CancelTaskInfo, NewTaskInfo, ResizeWorkersInfo, TaskStatus};
+use crate::core::retry_policy::{RetryBackoff, RetryPolicy, MAX_RETRY_DELAY_SECONDS};
+use crate::core::schedule::NewScheduleInfo;
+use crate::core::task_kinds::TaskKindRegistry;
+use crate::models::admin::WorkerCountModel;
+use crate::models::schedules::{
+    ListSchedulesResponse, ScheduleDefinitionModel, ScheduleStateModel,
+};
+use crate::models::tasks::{
+    CreateTaskDefinitionResponse, ListTasksQueryModel, ListTasksResponse, TaskDefinitionModel,
+    TaskEventModel, TaskEventsResponse, TaskStateModel,
+};
+use crate::registry::task_registry::{RegistryError, TaskQuery, TaskRegistry, TaskSort};
+
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const MAX_PAGE_LIMIT: usize = 1000;
+const MAX_WORKER_COUNT: usize = 1024;
+/// A year. Tasks further out can still be submitted with `run_at`.
+const MAX_DELAY_SECONDS: f64 = 31_536_000.0;
+/// A week
+const MAX_TIMEOUT_SECONDS: f64 = 604_800.0;
+const MAX_ATTEMPTS: u32 = 1000;
+const MAX_BACKOFF_MULTIPLIER: f64 = 100.0;
 
 pub struct ControlApi {
-    sender: Sender<NewTaskInfo>,
-    registry: Box<dyn TaskRegistry>,
+    cancel_sender: Sender<CancelTaskInfo>,
+    resize_sender: Sender<ResizeWorkersInfo>,
+    registry: Arc<dyn TaskRegistry>,
+    task_kinds: TaskKindRegistry,
 }
 
 impl ControlApi {
     pub fn new(
-        sender: Sender<NewTaskInfo>,
-        registry_factory: fn() -> Box<dyn TaskRegistry>,
+        cancel_sender: Sender<CancelTaskInfo>,
+        resize_sender: Sender<ResizeWorkersInfo>,
+        registry: Arc<dyn TaskRegistry>,
+        task_kinds: TaskKindRegistry,
     ) -> ControlApi {
         ControlApi {
-            sender,
-            registry: registry_factory(),
+            cancel_sender,
+            resize_sender,
+            registry,
+            task_kinds,
+        }
+    }
+}
+
+fn error_response(error: &RegistryError) -> HttpResponse {
+    match error {
+        RegistryError::NotFound { .. } | RegistryError::ScheduleNotFound { .. } => {
+            HttpResponse::NotFound().body(error.to_string())
+        }
+        RegistryError::Conflict { .. }
+        | RegistryError::ScheduleConflict { .. }
+        | RegistryError::InvalidTransition { .. } => {
+            HttpResponse::Conflict().body(error.to_string())
         }
+        RegistryError::InvalidCursor { .. } => HttpResponse::BadRequest().body(error.to_string()),
+        RegistryError::Storage { .. }
+        | RegistryError::CorruptRow { .. }
+        | RegistryError::UnsupportedSchema { .. } => {
+            println!("Registry error: {}", error);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Checks the fields of a task definition that the control loop turns into
+/// durations, shared by tasks and schedule templates.
+fn validate_task_definition(task_definition_model: &TaskDefinitionModel) -> Result<(), String> {
+    if task_definition_model
+        .timeout_seconds
+        .is_some_and(|x| !(x > 0.0 && x <= MAX_TIMEOUT_SECONDS))
+    {
+        return Err(format!(
+            "timeout_seconds must be greater than 0 and at most {MAX_TIMEOUT_SECONDS}"
+        ));
     }
+    validate_retry_policy(&task_definition_model.retry_policy)
 }
 
+fn validate_retry_policy(retry_policy: &RetryPolicy) -> Result<(), String> {
+    if !(1..=MAX_ATTEMPTS).contains(&retry_policy.max_attempts) {
+        return Err(format!("max_attempts must be between 1 and {MAX_ATTEMPTS}"));
+    }
+    let delays = match retry_policy.retry_backoff {
+        RetryBackoff::Fixed { delay_seconds } => vec![("delay_seconds", delay_seconds)],
+        RetryBackoff::Exponential {
+            initial_delay_seconds,
+            multiplier,
+            max_delay_seconds,
+            ..
+        } => {
+            if !(1.0..=MAX_BACKOFF_MULTIPLIER).contains(&multiplier) {
+                return Err(format!(
+                    "multiplier must be between 1 and {MAX_BACKOFF_MULTIPLIER}"
+                ));
+            }
+            vec![
+                ("initial_delay_seconds", initial_delay_seconds),
+                ("max_delay_seconds", max_delay_seconds),
+            ]
+        }
+    };
+    for (field, seconds) in delays {
+        if !(0.0..=MAX_RETRY_DELAY_SECONDS).contains(&seconds) {
+            return Err(format!(
+                "{field} must be between 0 and {MAX_RETRY_DELAY_SECONDS}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Creates the task. A task id can only be used once: submitting it again
+/// gets 409 Conflict with the existing task, unless both submissions carry the
+/// same `Idempotency-Key` header, in which case the retry succeeds without
+/// creating anything.
 #[post("/tasks/{task_id}")]
 pub async fn add_task(
+    request: HttpRequest,
     task_id: web::Path<String>,
     task: web::Json<TaskDefinitionModel>,
     control_api: web::Data<ControlApi>,
 ) -> impl Responder {
     println!("Adding task {:?}", task);
     let task_definition_model = task.into_inner();
-    let task_definition = (&task_definition_model).clone().create_task_definition();
+    if task_definition_model.run_at.is_some() && task_definition_model.delay_seconds.is_some() {
+        return HttpResponse::BadRequest().body("run_at and delay_seconds cannot both be set");
+    }
+    if task_definition_model
+        .delay_seconds
+        .is_some_and(|x| !(0.0..=MAX_DELAY_SECONDS).contains(&x))
+    {
+        return HttpResponse::BadRequest().body(format!(
+            "delay_seconds must be between 0 and {MAX_DELAY_SECONDS}"
+        ));
+    }
+    if let Err(message) = validate_task_definition(&task_definition_model) {
+        return HttpResponse::BadRequest().body(message);
+    }
+    let mut task_definition = task_definition_model.create_task_definition();
+    task_definition.idempotency_key = request
+        .headers()
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_string());
+
+    // Reject definitions the control loop would not be able to run
+    if let Err(error) = control_api
+        .task_kinds
+        .create(&task_definition.kind, &task_definition.parameters)
+    {
+        return HttpResponse::BadRequest().body(error.to_string());
+    }
+    match control_api
+        .registry
+        .creates_dependency_cycle(&task_id, &task_definition.depends_on)
+    {
+        Ok(false) => {}
+        Ok(true) => {
+            return HttpResponse::BadRequest()
+                .body(format!("task {task_id} has a dependency cycle"))
+        }
+        Err(error) => return error_response(&error),
+    }
 
     let new_task_info = NewTaskInfo {
         task_id: task_id.to_string(),
-        task_definition: (&task_definition).clone(),
+        task_definition,
     };
-    control_api.sender.send(new_task_info).unwrap();
+    match control_api.registry.create_task(&new_task_info) {
+        Ok(_) => {}
+        Err(RegistryError::Conflict { .. }) => {
+            let existing_task = match control_api.registry.get_task(&task_id) {
+                Ok(task_state) => task_state,
+                Err(error) => return error_response(&error),
+            };
+            let is_retry = new_task_info.task_definition.idempotency_key.is_some()
+                && existing_task.idempotency_key == new_task_info.task_definition.idempotency_key;
+            if !is_retry {
+                return HttpResponse::Conflict()
+                    .json(TaskStateModel::from_task_state(&existing_task));
+            }
+        }
+        Err(error) => return error_response(&error),
+    }
     let response = CreateTaskDefinitionResponse {
         task_id: task_id.to_string(),
         task_definition: task_definition_model,
@@ -44,15 +199,282 @@ pub async fn add_task(
     HttpResponse::Ok().json(response)
 }
 
+#[get("/tasks")]
+pub async fn list_tasks(
+    query: web::Query<ListTasksQueryModel>,
+    control_api: web::Data<ControlApi>,
+) -> impl Responder {
+    println!("Listing tasks {:?}", query);
+    let query = query.into_inner();
+    let mut statuses = HashSet::new();
+    for status in query.status.iter().flat_map(|s| s.split(',')) {
+        match TaskStatus::from_str(status.trim()) {
+            Ok(status) => statuses.insert(status),
+            Err(_) => return HttpResponse::BadRequest().body(format!("unknown status {status}")),
+        };
+    }
+    let sort = match query.sort.as_deref().map(TaskSort::from_str) {
+        None => TaskSort::default(),
+        Some(Ok(sort)) => sort,
+        Some(Err(_)) => {
+            return HttpResponse::BadRequest()
+                .body(format!("unknown sort {}", query.sort.unwrap_or_default()))
+        }
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if limit == 0 || limit > MAX_PAGE_LIMIT {
+        return HttpResponse::BadRequest()
+            .body(format!("limit must be between 1 and {MAX_PAGE_LIMIT}"));
+    }
+    let task_query = TaskQuery {
+        statuses,
+        created_after: query.created_after,
+        finished_after: query.finished_after,
+        queue: query.queue,
+        sort,
+        limit,
+        cursor: query.cursor,
+    };
+    match control_api.registry.get_task_page(&task_query) {
+        Ok(task_page) => HttpResponse::Ok().json(ListTasksResponse {
+            tasks: task_page
+                .tasks
+                .iter()
+                .map(TaskStateModel::from_task_state)
+                .collect(),
+            next_cursor: task_page.next_cursor,
+        }),
+        Err(error) => error_response(&error),
+    }
+}
+
 #[get("/tasks/{task_id}")]
 pub async fn get_task(
     task_id: web::Path<String>,
     control_api: web::Data<ControlApi>,
 ) -> impl Responder {
     println!("Getting task {:?}", task_id.to_string());
-    let task_state = control_api.registry.get_task(&task_id.to_string());
+    let task_state = control_api.registry.get_task(&task_id);
     match task_state {
         Ok(task_state) => HttpResponse::Ok().json(TaskStateModel::from_task_state(&task_state)),
-        Err(_) => HttpResponse::NotFound().finish(),
+        Err(error) => error_response(&error),
+    }
+}
+
+#[get("/tasks/{task_id}/events")]
+pub async fn get_task_events(
+    task_id: web::Path<String>,
+    control_api: web::Data<ControlApi>,
+) -> impl Responder {
+    println!("Getting events of task {:?}", task_id.to_string());
+    match control_api.registry.get_task_events(&task_id) {
+        Ok(events) => HttpResponse::Ok().json(TaskEventsResponse {
+            task_id: task_id.to_string(),
+            events: events.iter().map(TaskEventModel::from_task_event).collect(),
+        }),
+        Err(error) => error_response(&error),
+    }
+}
+
+#[delete("/tasks/{task_id}")]
+pub async fn cancel_task(
+    task_id: web::Path<String>,
+    control_api: web::Data<ControlApi>,
+) -> impl Responder {
+    println!("Cancelling task {:?}", task_id.to_string());
+    let task_state = match control_api.registry.get_task(&task_id) {
+        Ok(task_state) => task_state,
+        Err(error) => return error_response(&error),
+    };
+    if task_state.status.is_finished() {
+        return HttpResponse::Conflict().json(TaskStateModel::from_task_state(&task_state));
+    }
+    control_api
+        .cancel_sender
+        .send(CancelTaskInfo {
+            task_id: task_id.to_string(),
+        })
+        .unwrap();
+    HttpResponse::Accepted().json(TaskStateModel::from_task_state(&task_state))
+}
+
+/// Creates a schedule that submits a task from the template every time the
+/// cron expression comes due. Runs are counted from now, the tasks get ids
+/// like `{schedule_id}-20240101T020000Z`.
+#[post("/schedules/{schedule_id}")]
+pub async fn add_schedule(
+    schedule_id: web::Path<String>,
+    schedule: web::Json<ScheduleDefinitionModel>,
+    control_api: web::Data<ControlApi>,
+) -> impl Responder {
+    println!("Adding schedule {:?}", schedule);
+    let schedule_definition_model = schedule.into_inner();
+    if schedule_definition_model.task.run_at.is_some()
+        || schedule_definition_model.task.delay_seconds.is_some()
+    {
+        return HttpResponse::BadRequest()
+            .body("run_at and delay_seconds cannot be set on a scheduled task");
+    }
+    if let Err(message) = validate_task_definition(&schedule_definition_model.task) {
+        return HttpResponse::BadRequest().body(message);
+    }
+    let schedule_definition = schedule_definition_model.create_schedule_definition();
+    if let Err(error) = schedule_definition.cron_schedule() {
+        return HttpResponse::BadRequest().body(error.to_string());
+    }
+    let task_definition = &schedule_definition.task_definition;
+    if let Err(error) = control_api
+        .task_kinds
+        .create(&task_definition.kind, &task_definition.parameters)
+    {
+        return HttpResponse::BadRequest().body(error.to_string());
+    }
+
+    let new_schedule_info = NewScheduleInfo {
+        schedule_id: schedule_id.to_string(),
+        schedule_definition,
+    };
+    match control_api.registry.create_schedule(&new_schedule_info) {
+        Ok(schedule_state) => {
+            HttpResponse::Ok().json(ScheduleStateModel::from_schedule_state(&schedule_state))
+        }
+        Err(error) => error_response(&error),
+    }
+}
+
+#[get("/schedules")]
+pub async fn list_schedules(control_api: web::Data<ControlApi>) -> impl Responder {
+    println!("Listing schedules");
+    match control_api.registry.get_schedules() {
+        Ok(schedules) => HttpResponse::Ok().json(ListSchedulesResponse {
+            schedules: schedules
+                .iter()
+                .map(ScheduleStateModel::from_schedule_state)
+                .collect(),
+        }),
+        Err(error) => error_response(&error),
+    }
+}
+
+#[get("/schedules/{schedule_id}")]
+pub async fn get_schedule(
+    schedule_id: web::Path<String>,
+    control_api: web::Data<ControlApi>,
+) -> impl Responder {
+    println!("Getting schedule {:?}", schedule_id.to_string());
+    match control_api.registry.get_schedule(&schedule_id) {
+        Ok(schedule_state) => {
+            HttpResponse::Ok().json(ScheduleStateModel::from_schedule_state(&schedule_state))
+        }
+        Err(error) => error_response(&error),
+    }
+}
+
+/// Stops the schedule. Tasks it already submitted are left to run, they can
+/// be cancelled on their own.
+#[delete("/schedules/{schedule_id}")]
+pub async fn delete_schedule(
+    schedule_id: web::Path<String>,
+    control_api: web::Data<ControlApi>,
+) -> impl Responder {
+    println!("Deleting schedule {:?}", schedule_id.to_string());
+    match control_api.registry.delete_schedule(&schedule_id) {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(error) => error_response(&error),
+    }
+}
+
+/// Changes how many tasks the control loop runs at once. Takes effect on its
+/// next tick, running tasks are left to finish.
+#[put("/admin/workers")]
+pub async fn resize_workers(
+    worker_count: web::Json<WorkerCountModel>,
+    control_api: web::Data<ControlApi>,
+) -> impl Responder {
+    let worker_count = worker_count.worker_count;
+    println!("Resizing workers to {}", worker_count);
+    if worker_count == 0 || worker_count > MAX_WORKER_COUNT {
+        return HttpResponse::BadRequest().body(format!(
+            "worker_count must be between 1 and {MAX_WORKER_COUNT}"
+        ));
+    }
+    control_api
+        .resize_sender
+        .send(ResizeWorkersInfo { worker_count })
+        .unwrap();
+    HttpResponse::Accepted().json(WorkerCountModel { worker_count })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::control::control_api::validate_task_definition;
+    use crate::models::tasks::TaskDefinitionModel;
+
+    fn task_definition_model(fields: serde_json::Value) -> TaskDefinitionModel {
+        let mut task_definition = json!({"kind": "counting"});
+        task_definition
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(task_definition).unwrap()
+    }
+
+    #[test]
+    fn timeouts_are_validated() {
+        assert!(validate_task_definition(&task_definition_model(json!({}))).is_ok());
+        assert!(
+            validate_task_definition(&task_definition_model(json!({"timeout_seconds": 30.0})))
+                .is_ok()
+        );
+        for timeout_seconds in [-1.0, 0.0, 1e300] {
+            assert!(
+                validate_task_definition(&task_definition_model(
+                    json!({ "timeout_seconds": timeout_seconds })
+                ))
+                .is_err(),
+                "{timeout_seconds}"
+            );
+        }
+        let mut not_a_number = task_definition_model(json!({}));
+        not_a_number.timeout_seconds = Some(f64::NAN);
+        assert!(validate_task_definition(&not_a_number).is_err());
+    }
+
+    #[test]
+    fn retry_policies_are_validated() {
+        let exponential = |backoff: serde_json::Value| {
+            let mut retry_backoff = json!({"type": "exponential", "initial_delay_seconds": 1.0});
+            retry_backoff
+                .as_object_mut()
+                .unwrap()
+                .extend(backoff.as_object().unwrap().clone());
+            task_definition_model(json!({"max_attempts": 5, "retry_backoff": retry_backoff}))
+        };
+        assert!(validate_task_definition(&exponential(json!({}))).is_ok());
+        assert!(validate_task_definition(&task_definition_model(json!({
+            "max_attempts": 3,
+            "retry_backoff": {"type": "fixed", "delay_seconds": 10.0}
+        })))
+        .is_ok());
+
+        for invalid in [
+            exponential(json!({"multiplier": 1e300})),
+            exponential(json!({"multiplier": 0.5})),
+            exponential(json!({"initial_delay_seconds": -1.0})),
+            exponential(json!({"max_delay_seconds": 1e300})),
+            task_definition_model(json!({
+                "retry_backoff": {"type": "fixed", "delay_seconds": 1e300}
+            })),
+            task_definition_model(json!({"max_attempts": 0})),
+            task_definition_model(json!({"max_attempts": 1_000_000})),
+        ] {
+            assert!(
+                validate_task_definition(&invalid).is_err(),
+                "{:?}",
+                invalid.retry_policy
+            );
+        }
     }
 }
@@ -1,114 +1,1937 @@
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::core::core_types::{NewTaskInfo, TaskState, TaskStatus};
-use crate::registry::task_registry::TaskRegistry;
-use crate::threadpool::threadpool::ThreadPool;
+use chrono::{DateTime, Utc};
 
-struct TaskInfo {
-    task_id: String,
+use crate::core::core_types::{
+    AttemptError, CancelTaskInfo, NewTaskInfo, OrphanPolicy, ResizeWorkersInfo, TaskError,
+    TaskOutput, TaskState, TaskStatus,
+};
+use crate::core::schedule::{MissedRunPolicy, OverlapPolicy, ScheduleState};
+use crate::core::task_kinds::{TaskContext, TaskFailure, TaskKindRegistry};
+use crate::registry::task_registry::{RegistryError, TaskRegistry};
+use crate::threadpool::threadpool::{JobError, JobHandle, ThreadPool, DEFAULT_AGING_INTERVAL};
+
+/// At most this many missed runs of one schedule are caught up per tick, so a
+/// long outage cannot hold up the loop. The rest follow on later ticks.
+const MAX_CATCH_UP_RUNS: usize = 100;
+
+/// The priority of a `PENDING` task in milliseconds of aging, less when it
+/// became ready to run, like the rank of a job in the threadpool. A task ready
+/// one aging interval earlier ranks as one priority higher, so that urgent
+/// tasks cannot keep it waiting for a capped queue forever.
+fn aged_rank(task: &TaskState, aging_interval: Duration) -> i64 {
+    let ready_at = task
+        .next_attempt_at
+        .or(task.run_at)
+        .unwrap_or(task.created_at);
+    task.priority as i64 * aging_interval.as_millis() as i64 - ready_at.timestamp_millis()
+}
+
+enum UpstreamStatus {
+    Succeeded,
+    Waiting,
+    Failed { dependency: String },
+}
+
+/// How an attempt ended, as returned by its job.
+struct AttemptResult {
     status: TaskStatus,
+    output: Option<TaskOutput>,
+    error: Option<TaskError>,
+}
+
+/// An attempt handed to the threadpool whose end has not been recorded.
+struct InFlightTask {
+    attempt: u32,
+    stop_requested: Arc<AtomicBool>,
+    timeout: Option<Duration>,
+    handle: JobHandle<AttemptResult>,
+    queue: Option<String>,
+    /// Whether the task has been updated to `RUNNING`
+    running_recorded: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ControlLoopConfig {
+    /// Applies to orphaned tasks that do not set their own `on_orphaned`.
+    pub orphan_policy: OrphanPolicy,
+    /// How many tasks run at once. Can be changed while running through
+    /// `ControlLoop::resize_sender`.
+    pub worker_count: usize,
+    /// How many tasks of each queue may be handed to the threadpool at once,
+    /// e.g. 1 to run a queue's tasks one at a time. Queues without a limit,
+    /// and tasks without a queue, are only limited by `worker_count`.
+    pub queue_limits: HashMap<String, usize>,
+    /// How long a task waits to gain one level of priority, both while
+    /// `PENDING` and in the threadpool's queue.
+    pub aging_interval: Duration,
+}
+
+impl Default for ControlLoopConfig {
+    fn default() -> ControlLoopConfig {
+        ControlLoopConfig {
+            orphan_policy: OrphanPolicy::default(),
+            worker_count: 2,
+            queue_limits: HashMap::new(),
+            aging_interval: DEFAULT_AGING_INTERVAL,
+        }
+    }
 }
 
 pub struct ControlLoop<'a> {
     registry: &'a dyn TaskRegistry,
+    task_kinds: TaskKindRegistry,
     threadpool: ThreadPool,
-    task_definition_receiver: Receiver<NewTaskInfo>,
-    running_task_sender: Sender<TaskInfo>,
-    running_task_receiver: Receiver<TaskInfo>,
+    cancel_receiver: Receiver<CancelTaskInfo>,
+    /// Cancellations that could not be recorded yet, tried again every tick
+    pending_cancellations: Vec<CancelTaskInfo>,
+    resize_sender: Sender<ResizeWorkersInfo>,
+    resize_receiver: Receiver<ResizeWorkersInfo>,
+    in_flight: HashMap<String, InFlightTask>,
+    queue_limits: HashMap<String, usize>,
+    aging_interval: Duration,
 }
 
 impl ControlLoop<'_> {
     pub fn new(
         registry: &dyn TaskRegistry,
-        task_definition_receiver: Receiver<NewTaskInfo>,
-    ) -> ControlLoop {
-        let (sender, receiver) = mpsc::channel::<TaskInfo>();
-        return ControlLoop {
-            registry: registry,
-            threadpool: ThreadPool::new(2),
-            task_definition_receiver,
-            running_task_sender: sender,
-            running_task_receiver: receiver,
+        task_kinds: TaskKindRegistry,
+        cancel_receiver: Receiver<CancelTaskInfo>,
+        config: ControlLoopConfig,
+    ) -> ControlLoop<'_> {
+        let (resize_sender, resize_receiver) = mpsc::channel::<ResizeWorkersInfo>();
+        let control_loop = ControlLoop {
+            registry,
+            task_kinds,
+            threadpool: ThreadPool::with_aging_interval(config.worker_count, config.aging_interval),
+            cancel_receiver,
+            pending_cancellations: Vec::new(),
+            resize_sender,
+            resize_receiver,
+            in_flight: HashMap::new(),
+            queue_limits: config.queue_limits,
+            aging_interval: config.aging_interval,
         };
+        if let Err(error) = control_loop.recover_orphans(config.orphan_policy) {
+            println!("Could not recover orphaned tasks: {}", error);
+        }
+        control_loop
     }
 
-    pub fn run_once(&mut self) {
-        self.receive_new_tasks();
-        for task in self
+    /// Changes the number of workers on the next tick. Running tasks are not
+    /// interrupted, surplus workers retire once they are idle.
+    pub fn resize_sender(&self) -> Sender<ResizeWorkersInfo> {
+        self.resize_sender.clone()
+    }
+
+    pub fn worker_count(&self) -> usize {
+        self.threadpool.size()
+    }
+
+    /// A fresh control loop owns no attempts, so any task still `QUEUED` or
+    /// `RUNNING` was left behind by a process that died.
+    fn recover_orphans(&self, orphan_policy: OrphanPolicy) -> Result<(), RegistryError> {
+        let orphans: Vec<TaskState> = self
             .registry
-            .get_tasks(&HashSet::from([TaskStatus::PENDING]))
-        {
-            self.trigger_pending(&task)
+            .get_tasks(&HashSet::from([TaskStatus::QUEUED, TaskStatus::RUNNING]))?
+            .collect();
+        for task in orphans {
+            if let Err(error) = self.recover_orphan(&task, orphan_policy) {
+                println!("Could not recover orphaned task {}: {}", task.name, error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Requeued tasks are retried like any failed attempt, so a task that
+    /// keeps taking the runner down runs out of attempts.
+    fn recover_orphan(
+        &self,
+        task: &TaskState,
+        orphan_policy: OrphanPolicy,
+    ) -> Result<(), RegistryError> {
+        let error = TaskError::new("orphaned", "task was lost when the runner stopped");
+        match task.on_orphaned.unwrap_or(orphan_policy) {
+            OrphanPolicy::Fail => {
+                println!("Failing orphaned task {}", task.name);
+                self.registry.record_attempt_error(
+                    &task.name,
+                    &AttemptError {
+                        attempt: task.attempt,
+                        kind: error.kind.to_string(),
+                        message: error.message.to_string(),
+                    },
+                )?;
+                self.registry
+                    .fail_task(&task.name, TaskStatus::FAILED, &error)
+            }
+            OrphanPolicy::Requeue => {
+                println!("Requeueing orphaned task {}", task.name);
+                self.handle_failed_attempt(&task.name, task.attempt, &error, TaskStatus::FAILED)
+            }
+        }
+    }
+
+    /// Registry errors are logged and the affected task is left for a later
+    /// tick, so one bad row cannot stop the loop.
+    pub fn run_once(&mut self) {
+        self.receive_resizes();
+        self.receive_cancellations();
+        if let Err(error) = self.run_schedules() {
+            println!("Could not run schedules: {}", error);
+        }
+        if let Err(error) = self.release_due() {
+            println!("Could not release scheduled tasks: {}", error);
+        }
+        if let Err(error) = self.dispatch_pending() {
+            println!("Could not dispatch pending tasks: {}", error);
         }
         self.advance_running();
+        self.stop_timed_out();
+    }
+
+    fn run_schedules(&self) -> Result<(), RegistryError> {
+        let now = Utc::now();
+        for schedule_state in self.registry.get_schedules()? {
+            if let Err(error) = self.run_schedule(&schedule_state, now) {
+                println!("Could not run schedule {}: {}", schedule_state.id, error);
+            }
+        }
+        Ok(())
     }
 
-    fn receive_new_tasks(&mut self) {
-        let mut iterator = self.task_definition_receiver.try_iter();
-        loop {
-            let try_receiced_new_task_info = iterator.next();
-            if try_receiced_new_task_info.is_none() {
-                break;
+    /// Creates a task for each run of the schedule that has come due, as its
+    /// `MissedRunPolicy` and `OverlapPolicy` allow.
+    fn run_schedule(
+        &self,
+        schedule_state: &ScheduleState,
+        now: DateTime<Utc>,
+    ) -> Result<(), RegistryError> {
+        let schedule_definition = &schedule_state.schedule_definition;
+        let cron_schedule = match schedule_definition.cron_schedule() {
+            Ok(cron_schedule) => cron_schedule,
+            Err(error) => {
+                println!("Schedule {} cannot run: {}", schedule_state.id, error);
+                return Ok(());
+            }
+        };
+        let due_runs = schedule_state.due_runs(&cron_schedule, now);
+        let due_runs: Vec<DateTime<Utc>> = match schedule_definition.missed_runs {
+            MissedRunPolicy::Skip => due_runs.last().into_iter().collect(),
+            MissedRunPolicy::CatchUp => due_runs.take(MAX_CATCH_UP_RUNS).collect(),
+        };
+        let mut last_task_id = schedule_state.last_task_id.clone();
+        for run_at in due_runs {
+            if self.is_unfinished(last_task_id.as_deref())? {
+                match schedule_definition.overlap {
+                    OverlapPolicy::Skip => {
+                        println!(
+                            "Skipping run of schedule {} due at {}, the previous run has not finished",
+                            schedule_state.id, run_at
+                        );
+                        self.registry
+                            .record_schedule_run(&schedule_state.id, run_at, None)?;
+                        continue;
+                    }
+                    OverlapPolicy::Wait => return Ok(()),
+                    OverlapPolicy::Allow => {}
+                }
+            }
+            let task_id = schedule_state.task_id(run_at);
+            let mut task_definition = schedule_definition.task_definition.clone();
+            task_definition.run_at = None;
+            task_definition.idempotency_key = None;
+            println!("Schedule {} creating task {}", schedule_state.id, task_id);
+            match self.registry.create_task(&NewTaskInfo {
+                task_id: task_id.to_string(),
+                task_definition,
+            }) {
+                // Created before the runner stopped, without being recorded
+                Ok(_) | Err(RegistryError::Conflict { .. }) => {}
+                Err(error) => return Err(error),
             }
             self.registry
-                .create_task(&try_receiced_new_task_info.unwrap());
+                .record_schedule_run(&schedule_state.id, run_at, Some(&task_id))?;
+            last_task_id = Some(task_id);
         }
+        Ok(())
     }
 
-    fn trigger_pending(&self, task: &TaskState) {
-        assert_eq!(task.status, TaskStatus::PENDING);
-        let sender = (&self.running_task_sender).clone();
-        let cloned_task = task.clone();
-        self.threadpool.execute(move || {
-            sender
-                .send(TaskInfo {
-                    task_id: cloned_task.name.to_string(),
-                    status: TaskStatus::RUNNING,
-                })
-                .unwrap();
-            let result = cloned_task.run();
-            match result {
-                Ok(_) => {
-                    println!("Task {} succeeded", cloned_task.name);
-                    sender
-                        .send(TaskInfo {
-                            task_id: cloned_task.name.to_string(),
-                            status: TaskStatus::SUCCESS,
-                        })
-                        .unwrap();
+    /// Whether the task exists and has not finished.
+    fn is_unfinished(&self, task_id: Option<&str>) -> Result<bool, RegistryError> {
+        let Some(task_id) = task_id else {
+            return Ok(false);
+        };
+        match self.registry.get_task(task_id) {
+            Ok(task_state) => Ok(!task_state.status.is_finished()),
+            Err(RegistryError::NotFound { .. }) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Makes `SCHEDULED` tasks whose time has come `PENDING`, so that they are
+    /// dispatched in the same tick.
+    fn release_due(&self) -> Result<(), RegistryError> {
+        let due: Vec<TaskState> = self.registry.get_due_tasks(Utc::now())?.collect();
+        for task in due {
+            println!("Task {} is due", task.name);
+            if let Err(error) = self
+                .registry
+                .update_task_from_control_loop(&task.name, TaskStatus::PENDING)
+            {
+                println!("Could not release task {}: {}", task.name, error);
+            }
+        }
+        Ok(())
+    }
+
+    fn dispatch_pending(&mut self) -> Result<(), RegistryError> {
+        let now = Utc::now();
+        let mut pending: Vec<TaskState> = self
+            .registry
+            .get_tasks(&HashSet::from([TaskStatus::PENDING]))?
+            .collect();
+        // Stable, so tasks of the same rank keep their order
+        pending.sort_by_key(|task| Reverse(aged_rank(task, self.aging_interval)));
+        for task in pending {
+            if task.next_attempt_at.is_some_and(|x| x > now) {
+                continue;
+            }
+            if let Err(error) = self.dispatch(&task) {
+                println!("Could not dispatch task {}: {}", task.name, error);
+            }
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, task: &TaskState) -> Result<(), RegistryError> {
+        match self.upstream_status(task)? {
+            UpstreamStatus::Succeeded => self.trigger_pending(task),
+            UpstreamStatus::Waiting => Ok(()),
+            UpstreamStatus::Failed { dependency } => {
+                println!("Task {} has a failed dependency", task.name);
+                self.registry.fail_task(
+                    &task.name,
+                    TaskStatus::UPSTREAM_FAILED,
+                    &TaskError::new(
+                        "upstream_failed",
+                        &format!("dependency {dependency} did not succeed"),
+                    ),
+                )
+            }
+        }
+    }
+
+    fn receive_resizes(&mut self) {
+        // Only the latest request matters
+        if let Some(resize_workers_info) = self.resize_receiver.try_iter().last() {
+            let worker_count = resize_workers_info.worker_count;
+            if worker_count == 0 {
+                println!("Ignoring request to run without workers");
+                return;
+            }
+            println!("Resizing threadpool to {} workers", worker_count);
+            self.threadpool.resize(worker_count);
+        }
+    }
+
+    fn receive_cancellations(&mut self) {
+        let mut cancellations = std::mem::take(&mut self.pending_cancellations);
+        cancellations.extend(self.cancel_receiver.try_iter());
+        for cancel_task_info in cancellations {
+            let task_id = &cancel_task_info.task_id;
+            match self.cancel(task_id) {
+                Ok(()) => {}
+                Err(error @ RegistryError::NotFound { .. }) => {
+                    println!("Could not cancel task {}: {}", task_id, error);
+                }
+                Err(error) => {
+                    println!("Could not cancel task {}: {}", task_id, error);
+                    self.pending_cancellations.push(cancel_task_info);
                 }
-                Err(_) => {
-                    println!("Task {} failed", cloned_task.name);
-                    sender
-                        .send(TaskInfo {
-                            task_id: cloned_task.name.to_string(),
-                            status: TaskStatus::FAILED,
-                        })
-                        .unwrap();
+            }
+        }
+    }
+
+    fn cancel(&mut self, task_id: &str) -> Result<(), RegistryError> {
+        if self.registry.get_task(task_id)?.status.is_finished() {
+            return Ok(());
+        }
+        println!("Cancelling task {}", task_id);
+        self.registry
+            .update_task_from_control_loop(task_id, TaskStatus::CANCELLED)?;
+        // Whatever a running attempt returns after this is ignored
+        if let Some(in_flight_task) = self.in_flight.remove(task_id) {
+            if !in_flight_task.handle.cancel() {
+                in_flight_task.stop_requested.store(true, Ordering::SeqCst);
+                self.threadpool.abandon(&in_flight_task.handle);
+            }
+        }
+        Ok(())
+    }
+
+    fn upstream_status(&self, task: &TaskState) -> Result<UpstreamStatus, RegistryError> {
+        let mut upstream_status = UpstreamStatus::Succeeded;
+        for dependency in &task.depends_on {
+            match self.registry.get_task(dependency).map(|x| x.status) {
+                Ok(TaskStatus::SUCCESS) => {}
+                Ok(status) if status.is_finished() => {
+                    return Ok(UpstreamStatus::Failed {
+                        dependency: dependency.to_string(),
+                    })
                 }
+                // Not submitted yet
+                Err(RegistryError::NotFound { .. }) => upstream_status = UpstreamStatus::Waiting,
+                Err(error) => return Err(error),
+                Ok(_) => upstream_status = UpstreamStatus::Waiting,
             }
-        });
+        }
+        Ok(upstream_status)
     }
 
+    fn trigger_pending(&mut self, task: &TaskState) -> Result<(), RegistryError> {
+        assert_eq!(task.status, TaskStatus::PENDING);
+        let runnable_task = match self.task_kinds.create(&task.kind, &task.parameters) {
+            Ok(runnable_task) => runnable_task,
+            Err(error) => {
+                println!("Task {} could not be created: {}", task.name, error);
+                return self.registry.fail_task(
+                    &task.name,
+                    TaskStatus::FAILED,
+                    &TaskError::new("invalid_task", &error.to_string()),
+                );
+            }
+        };
+        let timeout = match task.timeout_seconds.map(Duration::try_from_secs_f64) {
+            None => None,
+            Some(Ok(timeout)) => Some(timeout),
+            // Only rows written before the API checked it
+            Some(Err(error)) => {
+                println!("Task {} has an invalid timeout: {}", task.name, error);
+                return self.registry.fail_task(
+                    &task.name,
+                    TaskStatus::FAILED,
+                    &TaskError::new("invalid_task", &format!("invalid timeout_seconds: {error}")),
+                );
+            }
+        };
+        if self.queue_is_full(task) {
+            return Ok(());
+        }
+        // Claim before handing to the threadpool so that a task still waiting
+        // in the pool's queue is not dispatched again on the next tick.
+        if !self.registry.claim_task(&task.name)? {
+            return Ok(());
+        }
+        let task_id = task.name.to_string();
+        let attempt = task.attempt + 1;
+        let stop_requested = Arc::new(AtomicBool::new(false));
+        let job_stop_requested = stop_requested.clone();
+        let handle = self
+            .threadpool
+            .submit_with_priority(task.priority, move || {
+                let context = TaskContext::new(job_stop_requested, timeout);
+                match runnable_task.run(&context) {
+                    Ok(output) => {
+                        println!("Task {} succeeded", task_id);
+                        AttemptResult {
+                            status: TaskStatus::SUCCESS,
+                            output: Some(output),
+                            error: None,
+                        }
+                    }
+                    Err(failure) => {
+                        println!("Task {} failed: {}", task_id, failure.error);
+                        let status = if context.timed_out() {
+                            TaskStatus::TIMED_OUT
+                        } else {
+                            TaskStatus::FAILED
+                        };
+                        AttemptResult {
+                            status,
+                            error: Some(failure.task_error()),
+                            output: Some(failure.output),
+                        }
+                    }
+                }
+            });
+        self.in_flight.insert(
+            task.name.to_string(),
+            InFlightTask {
+                attempt,
+                stop_requested,
+                timeout,
+                handle,
+                queue: task.queue.clone(),
+                running_recorded: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// Whether as many tasks of the queue of `task` are in flight as its
+    /// limit allows, in which case `task` waits for a later tick.
+    fn queue_is_full(&self, task: &TaskState) -> bool {
+        let Some(queue) = &task.queue else {
+            return false;
+        };
+        let Some(&limit) = self.queue_limits.get(queue) else {
+            return false;
+        };
+        let in_flight = self
+            .in_flight
+            .values()
+            .filter(|in_flight_task| in_flight_task.queue.as_ref() == Some(queue))
+            .count();
+        in_flight >= limit
+    }
+
+    /// Records attempts that workers have picked up or finished since the
+    /// last tick.
     fn advance_running(&mut self) {
-        let mut iterator = self.running_task_receiver.try_iter();
-        loop {
-            let try_received_task = iterator.next();
-            if try_received_task.is_none() {
-                break;
+        let task_ids: Vec<String> = self.in_flight.keys().cloned().collect();
+        for task_id in task_ids {
+            if let Err(error) = self.record_running(&task_id) {
+                // Its result waits in the handle until `RUNNING` is recorded
+                println!("Could not update task {}: {}", task_id, error);
+                continue;
             }
-            let received_task = try_received_task.unwrap();
+            let in_flight_task = self.in_flight.get_mut(&task_id).unwrap();
+            let attempt_result = match in_flight_task.handle.try_result() {
+                Some(Ok(attempt_result)) => attempt_result,
+                Some(Err(JobError::Panicked { message })) => {
+                    println!("Task {} panicked: {}", task_id, message);
+                    AttemptResult {
+                        status: TaskStatus::FAILED,
+                        output: None,
+                        error: Some(TaskFailure::from_panic(&message).task_error()),
+                    }
+                }
+                // Only cancelled along with removing it from `in_flight`
+                Some(Err(JobError::Cancelled)) | None => continue,
+            };
+            let attempt = self.in_flight.remove(&task_id).unwrap().attempt;
             print!(
                 "Updating task {} to status {}...",
-                received_task.task_id,
-                received_task.status.to_string()
+                task_id, attempt_result.status
             );
-            self.registry.update_task_from_control_loop(
-                &received_task.task_id,
-                (&received_task.status).clone(),
+            if let Err(error) = self.record_update(&task_id, attempt, &attempt_result) {
+                println!("Could not update task {}: {}", task_id, error);
+            }
+        }
+    }
+
+    /// Updates the task to `RUNNING` once a worker has picked up its attempt.
+    fn record_running(&mut self, task_id: &str) -> Result<(), RegistryError> {
+        let in_flight_task = self.in_flight.get_mut(task_id).unwrap();
+        if in_flight_task.running_recorded || in_flight_task.handle.started_at().is_none() {
+            return Ok(());
+        }
+        print!(
+            "Updating task {} to status {}...",
+            task_id,
+            TaskStatus::RUNNING
+        );
+        self.registry
+            .update_task_from_control_loop(task_id, TaskStatus::RUNNING)?;
+        in_flight_task.running_recorded = true;
+        Ok(())
+    }
+
+    fn record_update(
+        &self,
+        task_id: &str,
+        attempt: u32,
+        attempt_result: &AttemptResult,
+    ) -> Result<(), RegistryError> {
+        if let Some(output) = &attempt_result.output {
+            self.registry.set_task_output(task_id, output)?;
+        }
+        match &attempt_result.error {
+            Some(error) => {
+                self.handle_failed_attempt(task_id, attempt, error, attempt_result.status.clone())
+            }
+            None => self
+                .registry
+                .update_task_from_control_loop(task_id, attempt_result.status.clone()),
+        }
+    }
+
+    /// Abandons attempts that have run past their timeout and asks them to
+    /// stop. Processes started by shell command tasks are killed.
+    fn stop_timed_out(&mut self) {
+        let now = Instant::now();
+        let timed_out: Vec<String> = self
+            .in_flight
+            .iter()
+            .filter(|(_, in_flight_task)| {
+                match (in_flight_task.handle.started_at(), in_flight_task.timeout) {
+                    (Some(started_at), Some(timeout)) => started_at
+                        .checked_add(timeout)
+                        .is_some_and(|deadline| now >= deadline),
+                    _ => false,
+                }
+            })
+            .map(|(task_id, _)| task_id.to_string())
+            .collect();
+        for task_id in timed_out {
+            // The attempt may have started since `advance_running` looked, and
+            // a task still `QUEUED` cannot time out
+            let result = self.record_running(&task_id).and_then(|()| {
+                let in_flight_task = &self.in_flight[&task_id];
+                in_flight_task.stop_requested.store(true, Ordering::SeqCst);
+                println!("Task {} timed out", task_id);
+                let error = TaskError::new(
+                    "timed_out",
+                    &format!("task timed out after {:?}", in_flight_task.timeout.unwrap()),
+                );
+                self.handle_failed_attempt(
+                    &task_id,
+                    in_flight_task.attempt,
+                    &error,
+                    TaskStatus::TIMED_OUT,
+                )
+            });
+            match result {
+                Ok(()) => {
+                    // A task that does not check its context would hold on to
+                    // its worker until it returns
+                    let in_flight_task = self.in_flight.remove(&task_id).unwrap();
+                    self.threadpool.abandon(&in_flight_task.handle);
+                }
+                // Kept to be tried again on the next tick
+                Err(error) => println!("Could not update task {}: {}", task_id, error),
+            }
+        }
+    }
+
+    /// Either schedules another attempt of a failed task or, once its retry
+    /// policy is exhausted, fails it with `final_status`.
+    fn handle_failed_attempt(
+        &self,
+        task_id: &str,
+        attempt: u32,
+        error: &TaskError,
+        final_status: TaskStatus,
+    ) -> Result<(), RegistryError> {
+        self.registry.record_attempt_error(
+            task_id,
+            &AttemptError {
+                attempt,
+                kind: error.kind.to_string(),
+                message: error.message.to_string(),
+            },
+        )?;
+        let retry_policy = self.registry.get_task(task_id)?.retry_policy;
+        if !retry_policy.should_retry(attempt, &error.kind) {
+            return self.registry.fail_task(task_id, final_status, error);
+        }
+        let delay = retry_policy.retry_backoff.delay(attempt);
+        match chrono::Duration::from_std(delay)
+            .ok()
+            .and_then(|delay| Utc::now().checked_add_signed(delay))
+        {
+            Some(next_attempt_at) => {
+                println!("Retrying task {} at {}", task_id, next_attempt_at);
+                self.registry.schedule_retry(task_id, next_attempt_at)
+            }
+            None => {
+                println!("Task {} cannot be retried after {:?}", task_id, delay);
+                self.registry.fail_task(task_id, final_status, error)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::mpsc::Sender;
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use chrono::{DateTime, Utc};
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use crate::control::control_loop::{ControlLoop, ControlLoopConfig};
+    use crate::core::core_types::{
+        timestamp_now, AttemptError, CancelTaskInfo, NewTaskInfo, OrphanPolicy, ResizeWorkersInfo,
+        TaskDefinition, TaskError, TaskEvent, TaskOutput, TaskState, TaskStatus,
+    };
+    use crate::core::retry_policy::{RetryBackoff, RetryPolicy};
+    use crate::core::schedule::{
+        NewScheduleInfo, OverlapPolicy, ScheduleDefinition, ScheduleState,
+    };
+    use crate::core::task_kinds::{Task, TaskContext, TaskFailure, TaskKindRegistry};
+    use crate::registry::task_registry::{RegistryError, TaskPage, TaskQuery, TaskRegistry};
+    use crate::registry::task_registry_sqlite::{TablePermanance, TaskRegistrySqlite};
+
+    static RUN_COUNTS: Mutex<Option<HashMap<String, usize>>> = Mutex::new(None);
+
+    #[derive(Deserialize)]
+    struct CountingTask {
+        id: String,
+        #[serde(default)]
+        fail: bool,
+        /// Fail this many runs before succeeding
+        #[serde(default)]
+        fail_runs: usize,
+        /// Sleep without checking the context, like a task that hangs
+        #[serde(default)]
+        hang_ms: u64,
+        #[serde(default)]
+        panic: bool,
+    }
+
+    impl CountingTask {
+        fn from_parameters(
+            parameters: &serde_json::Value,
+        ) -> Result<Box<dyn Task>, serde_json::Error> {
+            Ok(Box::new(CountingTask::deserialize(parameters)?))
+        }
+    }
+
+    impl Task for CountingTask {
+        fn run(&self, _context: &TaskContext) -> Result<TaskOutput, TaskFailure> {
+            thread::sleep(Duration::from_millis(100 + self.hang_ms));
+            let mut run_counts = RUN_COUNTS.lock().unwrap();
+            let run_count = run_counts
+                .get_or_insert_with(HashMap::new)
+                .entry(self.id.to_string())
+                .or_insert(0);
+            *run_count += 1;
+            if self.panic {
+                drop(run_counts);
+                panic!("counting task {} panicked on purpose", self.id);
+            }
+            if self.fail || *run_count <= self.fail_runs {
+                return Err(std::io::Error::other("failing on purpose").into());
+            }
+            Ok(TaskOutput::default())
+        }
+    }
+
+    /// Fails the next update to the status in `fail_update`.
+    struct FlakyRegistry {
+        registry: TaskRegistrySqlite,
+        fail_update: Mutex<Option<TaskStatus>>,
+    }
+
+    impl FlakyRegistry {
+        fn new() -> FlakyRegistry {
+            FlakyRegistry {
+                registry: TaskRegistrySqlite::new(
+                    ":memory:",
+                    "test_table",
+                    TablePermanance::DropOnClose,
+                )
+                .unwrap(),
+                fail_update: Mutex::new(None),
+            }
+        }
+    }
+
+    impl TaskRegistry for FlakyRegistry {
+        fn get_task(&self, task_id: &str) -> Result<TaskState, RegistryError> {
+            self.registry.get_task(task_id)
+        }
+        fn update_task_from_control_loop(
+            &self,
+            task_id: &str,
+            status: TaskStatus,
+        ) -> Result<(), RegistryError> {
+            let mut fail_update = self.fail_update.lock().unwrap();
+            if fail_update.as_ref() == Some(&status) {
+                *fail_update = None;
+                return Err(RegistryError::Storage {
+                    message: "failing on purpose".to_string(),
+                });
+            }
+            self.registry.update_task_from_control_loop(task_id, status)
+        }
+        fn fail_task(
+            &self,
+            task_id: &str,
+            status: TaskStatus,
+            error: &TaskError,
+        ) -> Result<(), RegistryError> {
+            self.registry.fail_task(task_id, status, error)
+        }
+        fn claim_task(&self, task_id: &str) -> Result<bool, RegistryError> {
+            self.registry.claim_task(task_id)
+        }
+        fn record_attempt_error(
+            &self,
+            task_id: &str,
+            attempt_error: &AttemptError,
+        ) -> Result<(), RegistryError> {
+            self.registry.record_attempt_error(task_id, attempt_error)
+        }
+        fn schedule_retry(
+            &self,
+            task_id: &str,
+            next_attempt_at: DateTime<Utc>,
+        ) -> Result<(), RegistryError> {
+            self.registry.schedule_retry(task_id, next_attempt_at)
+        }
+        fn set_task_output(&self, task_id: &str, output: &TaskOutput) -> Result<(), RegistryError> {
+            self.registry.set_task_output(task_id, output)
+        }
+        fn create_task(&self, new_task_info: &NewTaskInfo) -> Result<TaskState, RegistryError> {
+            self.registry.create_task(new_task_info)
+        }
+        fn get_tasks<'a>(
+            &'a self,
+            statuses: &'a HashSet<TaskStatus>,
+        ) -> Result<Box<dyn Iterator<Item = TaskState> + 'a>, RegistryError> {
+            self.registry.get_tasks(statuses)
+        }
+        fn get_due_tasks<'a>(
+            &'a self,
+            now: DateTime<Utc>,
+        ) -> Result<Box<dyn Iterator<Item = TaskState> + 'a>, RegistryError> {
+            self.registry.get_due_tasks(now)
+        }
+        fn get_task_page(&self, query: &TaskQuery) -> Result<TaskPage, RegistryError> {
+            self.registry.get_task_page(query)
+        }
+        fn get_task_events(&self, task_id: &str) -> Result<Vec<TaskEvent>, RegistryError> {
+            self.registry.get_task_events(task_id)
+        }
+        fn create_schedule(
+            &self,
+            new_schedule_info: &NewScheduleInfo,
+        ) -> Result<ScheduleState, RegistryError> {
+            self.registry.create_schedule(new_schedule_info)
+        }
+        fn get_schedule(&self, schedule_id: &str) -> Result<ScheduleState, RegistryError> {
+            self.registry.get_schedule(schedule_id)
+        }
+        fn get_schedules(&self) -> Result<Vec<ScheduleState>, RegistryError> {
+            self.registry.get_schedules()
+        }
+        fn delete_schedule(&self, schedule_id: &str) -> Result<(), RegistryError> {
+            self.registry.delete_schedule(schedule_id)
+        }
+        fn record_schedule_run(
+            &self,
+            schedule_id: &str,
+            run_at: DateTime<Utc>,
+            task_id: Option<&str>,
+        ) -> Result<(), RegistryError> {
+            self.registry
+                .record_schedule_run(schedule_id, run_at, task_id)
+        }
+    }
+
+    fn run_count(id: &str) -> usize {
+        let run_counts = RUN_COUNTS.lock().unwrap();
+        run_counts
+            .as_ref()
+            .and_then(|x| x.get(id))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn counting_task(
+        task_id: &str,
+        parameters: serde_json::Value,
+        depends_on: &[&str],
+    ) -> NewTaskInfo {
+        NewTaskInfo {
+            task_id: task_id.to_string(),
+            task_definition: TaskDefinition {
+                kind: "counting".to_string(),
+                parameters,
+                depends_on: depends_on.iter().map(|x| x.to_string()).collect(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// A control loop that runs the built in kinds and `CountingTask` as
+    /// `counting`.
+    fn control_loop_with_counting_kind(
+        registry: &dyn TaskRegistry,
+        config: ControlLoopConfig,
+    ) -> (ControlLoop<'_>, Sender<CancelTaskInfo>) {
+        let mut task_kinds = TaskKindRegistry::with_builtin_kinds();
+        task_kinds.register("counting", CountingTask::from_parameters);
+        let (cancel_sender, cancel_receiver) = mpsc::channel();
+        let control_loop = ControlLoop::new(registry, task_kinds, cancel_receiver, config);
+        (control_loop, cancel_sender)
+    }
+
+    fn run_until_finished(
+        control_loop: &mut ControlLoop,
+        registry: &dyn TaskRegistry,
+        task_ids: &[&str],
+    ) {
+        let finished = HashSet::from([
+            TaskStatus::SUCCESS,
+            TaskStatus::FAILED,
+            TaskStatus::UPSTREAM_FAILED,
+            TaskStatus::TIMED_OUT,
+            TaskStatus::CANCELLED,
+        ]);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while task_ids.iter().any(|task_id| {
+            registry
+                .get_task(task_id)
+                .map(|t| !finished.contains(&t.status))
+                .unwrap_or(true)
+        }) {
+            assert!(Instant::now() < deadline, "tasks did not finish in time");
+            control_loop.run_once();
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn tasks_run_once_under_saturated_pool() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        // Many more tasks than workers so that most of them wait in the pool's
+        // queue across several ticks.
+        let task_ids: Vec<String> = (0..8).map(|i| format!("saturated {i}")).collect();
+        for task_id in &task_ids {
+            registry
+                .create_task(&counting_task(task_id, json!({ "id": task_id }), &[]))
+                .unwrap();
+        }
+        let task_ids: Vec<&str> = task_ids.iter().map(|x| x.as_str()).collect();
+        run_until_finished(&mut control_loop, &registry, &task_ids);
+
+        for task_id in task_ids {
+            assert_eq!(
+                registry.get_task(task_id).unwrap().status,
+                TaskStatus::SUCCESS
             );
+            assert_eq!(run_count(task_id), 1);
+        }
+    }
+
+    #[test]
+    fn higher_priority_tasks_run_first() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) = control_loop_with_counting_kind(
+            &registry,
+            ControlLoopConfig {
+                worker_count: 1,
+                ..Default::default()
+            },
+        );
+
+        // Submitted least urgent first
+        for (task_id, priority) in [
+            ("priority batch", -1),
+            ("priority normal", 0),
+            ("priority urgent", 5),
+        ] {
+            let mut new_task_info = counting_task(task_id, json!({ "id": task_id }), &[]);
+            new_task_info.task_definition.priority = priority;
+            registry.create_task(&new_task_info).unwrap();
+        }
+        let task_ids = ["priority urgent", "priority normal", "priority batch"];
+        run_until_finished(&mut control_loop, &registry, &task_ids);
+
+        let started_at: Vec<_> = task_ids
+            .iter()
+            .map(|task_id| registry.get_task(task_id).unwrap().started_at.unwrap())
+            .collect();
+        assert!(started_at[0] < started_at[1]);
+        assert!(started_at[1] < started_at[2]);
+    }
+
+    /// Runs the loop until `task_id` finishes, keeping two tasks of `priority`
+    /// waiting in `queue` all along. Returns how many of them finished first.
+    fn run_while_feeding(
+        control_loop: &mut ControlLoop,
+        registry: &dyn TaskRegistry,
+        task_id: &str,
+        queue: &str,
+        priority: i32,
+    ) -> usize {
+        let mut fed: Vec<String> = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !registry.get_task(task_id).unwrap().status.is_finished() {
+            assert!(Instant::now() < deadline, "{task_id} starved");
+            let waiting = fed
+                .iter()
+                .filter(|x| registry.get_task(x).unwrap().status == TaskStatus::PENDING)
+                .count();
+            for _ in waiting..2 {
+                let fed_id = format!("{task_id} fed {}", fed.len());
+                let mut new_task_info = counting_task(&fed_id, json!({ "id": fed_id }), &[]);
+                new_task_info.task_definition.queue = Some(queue.to_string());
+                new_task_info.task_definition.priority = priority;
+                registry.create_task(&new_task_info).unwrap();
+                fed.push(fed_id);
+            }
+            control_loop.run_once();
+            thread::sleep(Duration::from_millis(10));
+        }
+        fed.iter()
+            .filter(|x| registry.get_task(x).unwrap().status == TaskStatus::SUCCESS)
+            .count()
+    }
+
+    #[test]
+    fn waiting_tasks_age_past_urgent_ones() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) = control_loop_with_counting_kind(
+            &registry,
+            ControlLoopConfig {
+                queue_limits: HashMap::from([("aging".to_string(), 1)]),
+                aging_interval: Duration::from_millis(100),
+                ..Default::default()
+            },
+        );
+
+        let mut batch = counting_task("aging batch", json!({"id": "aging batch"}), &[]);
+        batch.task_definition.queue = Some("aging".to_string());
+        registry.create_task(&batch).unwrap();
+        // Ranks above the batch task until it has waited five aging intervals
+        let urgent_first =
+            run_while_feeding(&mut control_loop, &registry, "aging batch", "aging", 5);
+        assert_eq!(
+            registry.get_task("aging batch").unwrap().status,
+            TaskStatus::SUCCESS
+        );
+        assert!(urgent_first >= 2, "{urgent_first}");
+    }
+
+    #[test]
+    fn queue_limits_cap_concurrency() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) = control_loop_with_counting_kind(
+            &registry,
+            ControlLoopConfig {
+                worker_count: 4,
+                queue_limits: HashMap::from([("exclusive".to_string(), 1)]),
+                ..Default::default()
+            },
+        );
+
+        let task_ids: Vec<String> = (0..3).map(|i| format!("queue exclusive {i}")).collect();
+        for task_id in &task_ids {
+            let mut new_task_info = counting_task(task_id, json!({ "id": task_id }), &[]);
+            new_task_info.task_definition.queue = Some("exclusive".to_string());
+            registry.create_task(&new_task_info).unwrap();
+        }
+        registry
+            .create_task(&counting_task(
+                "queue unlimited",
+                json!({"id": "queue unlimited"}),
+                &[],
+            ))
+            .unwrap();
+        let mut all_task_ids: Vec<&str> = task_ids.iter().map(|x| x.as_str()).collect();
+        all_task_ids.push("queue unlimited");
+        run_until_finished(&mut control_loop, &registry, &all_task_ids);
+
+        let mut exclusive: Vec<_> = task_ids
+            .iter()
+            .map(|task_id| registry.get_task(task_id).unwrap())
+            .collect();
+        exclusive.sort_by_key(|task_state| task_state.started_at);
+        for pair in exclusive.windows(2) {
+            assert_eq!(pair[0].status, TaskStatus::SUCCESS);
+            // One at a time
+            assert!(pair[1].started_at.unwrap() >= pair[0].finished_at.unwrap());
+        }
+        let unlimited = registry.get_task("queue unlimited").unwrap();
+        assert_eq!(unlimited.status, TaskStatus::SUCCESS);
+        // Did not wait behind the exclusive queue
+        assert!(unlimited.finished_at.unwrap() <= exclusive[2].started_at.unwrap());
+    }
+
+    #[test]
+    fn capped_queues_do_not_starve_light_tasks() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) = control_loop_with_counting_kind(
+            &registry,
+            ControlLoopConfig {
+                worker_count: 4,
+                queue_limits: HashMap::from([("shared".to_string(), 2)]),
+                aging_interval: Duration::from_millis(50),
+                ..Default::default()
+            },
+        );
+
+        let mut light = counting_task("capped light", json!({"id": "capped light"}), &[]);
+        light.task_definition.queue = Some("shared".to_string());
+        light.task_definition.priority = -2;
+        registry.create_task(&light).unwrap();
+        registry
+            .create_task(&counting_task(
+                "capped unqueued",
+                json!({"id": "capped unqueued"}),
+                &[],
+            ))
+            .unwrap();
+        // Heavy tasks keep the queue at its cap
+        let heavy_first =
+            run_while_feeding(&mut control_loop, &registry, "capped light", "shared", 8);
+        assert!(heavy_first >= 2, "{heavy_first}");
+
+        let light = registry.get_task("capped light").unwrap();
+        assert_eq!(light.status, TaskStatus::SUCCESS);
+        let unqueued = registry.get_task("capped unqueued").unwrap();
+        assert_eq!(unqueued.status, TaskStatus::SUCCESS);
+        // Not held back by the capped queue
+        assert!(unqueued.finished_at.unwrap() < light.started_at.unwrap());
+    }
+
+    #[test]
+    fn scheduled_tasks_wait_until_due() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        let run_at = timestamp_now() + chrono::Duration::milliseconds(300);
+        let mut new_task_info = counting_task("scheduled", json!({"id": "scheduled"}), &[]);
+        new_task_info.task_definition.run_at = Some(run_at);
+        registry.create_task(&new_task_info).unwrap();
+        control_loop.run_once();
+        assert_eq!(
+            registry.get_task("scheduled").unwrap().status,
+            TaskStatus::SCHEDULED
+        );
+
+        run_until_finished(&mut control_loop, &registry, &["scheduled"]);
+        let task_state = registry.get_task("scheduled").unwrap();
+        assert_eq!(task_state.status, TaskStatus::SUCCESS);
+        assert!(task_state.queued_at.unwrap() >= run_at);
+        assert_eq!(run_count("scheduled"), 1);
+    }
+
+    fn every_second(schedule_id: &str, hang_ms: u64, overlap: OverlapPolicy) -> NewScheduleInfo {
+        NewScheduleInfo {
+            schedule_id: schedule_id.to_string(),
+            schedule_definition: ScheduleDefinition {
+                cron: "* * * * * *".to_string(),
+                timezone: "UTC".to_string(),
+                task_definition: counting_task(
+                    "",
+                    json!({"id": schedule_id, "hang_ms": hang_ms}),
+                    &[],
+                )
+                .task_definition,
+                overlap,
+                ..Default::default()
+            },
         }
     }
+
+    fn run_for(control_loop: &mut ControlLoop, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            control_loop.run_once();
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn schedules_create_tasks() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        registry
+            .create_schedule(&every_second(
+                "scheduled every second",
+                0,
+                OverlapPolicy::Skip,
+            ))
+            .unwrap();
+        run_for(&mut control_loop, Duration::from_millis(2500));
+        let schedule_state = registry.get_schedule("scheduled every second").unwrap();
+        let last_run_at = schedule_state.last_run_at.unwrap();
+        let last_task_id = schedule_state.last_task_id.clone().unwrap();
+        assert_eq!(last_task_id, schedule_state.task_id(last_run_at));
+
+        run_until_finished(&mut control_loop, &registry, &[&last_task_id]);
+        assert_eq!(
+            registry.get_task(&last_task_id).unwrap().status,
+            TaskStatus::SUCCESS
+        );
+        assert!(run_count("scheduled every second") >= 2);
+    }
+
+    #[test]
+    fn overlapping_schedule_runs_are_skipped() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        registry
+            .create_schedule(&every_second(
+                "overlapping schedule",
+                3000,
+                OverlapPolicy::Skip,
+            ))
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let first_task_id = loop {
+            assert!(Instant::now() < deadline, "schedule did not run in time");
+            control_loop.run_once();
+            if let Some(task_id) = registry
+                .get_schedule("overlapping schedule")
+                .unwrap()
+                .last_task_id
+            {
+                break task_id;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        // Later runs come due while the first is still running
+        run_for(&mut control_loop, Duration::from_millis(1500));
+        let schedule_state = registry.get_schedule("overlapping schedule").unwrap();
+        assert_eq!(schedule_state.last_task_id, Some(first_task_id.clone()));
+        assert!(schedule_state.task_id(schedule_state.last_run_at.unwrap()) > first_task_id);
+        assert_eq!(
+            registry.get_task(&first_task_id).unwrap().status,
+            TaskStatus::RUNNING
+        );
+        registry.delete_schedule("overlapping schedule").unwrap();
+        run_until_finished(&mut control_loop, &registry, &[&first_task_id]);
+    }
+
+    #[test]
+    fn dependent_tasks_wait_for_upstream() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        // Submitted before the task it depends on
+        registry
+            .create_task(&counting_task(
+                "dag downstream",
+                json!({"id": "dag downstream"}),
+                &["dag upstream"],
+            ))
+            .unwrap();
+        registry
+            .create_task(&counting_task(
+                "dag upstream",
+                json!({"id": "dag upstream"}),
+                &[],
+            ))
+            .unwrap();
+        registry
+            .create_task(&counting_task(
+                "dag failing",
+                json!({"id": "dag failing", "fail": true}),
+                &[],
+            ))
+            .unwrap();
+        registry
+            .create_task(&counting_task(
+                "dag skipped",
+                json!({"id": "dag skipped"}),
+                &["dag upstream", "dag failing"],
+            ))
+            .unwrap();
+        registry
+            .create_task(&counting_task(
+                "dag skipped transitively",
+                json!({"id": "dag skipped transitively"}),
+                &["dag skipped"],
+            ))
+            .unwrap();
+        let task_ids = [
+            "dag downstream",
+            "dag upstream",
+            "dag failing",
+            "dag skipped",
+            "dag skipped transitively",
+        ];
+        run_until_finished(&mut control_loop, &registry, &task_ids);
+
+        let status = |task_id| registry.get_task(task_id).unwrap().status;
+        assert_eq!(status("dag downstream"), TaskStatus::SUCCESS);
+        assert_eq!(status("dag failing"), TaskStatus::FAILED);
+        assert_eq!(status("dag skipped"), TaskStatus::UPSTREAM_FAILED);
+        assert_eq!(
+            status("dag skipped transitively"),
+            TaskStatus::UPSTREAM_FAILED
+        );
+        assert_eq!(
+            registry
+                .get_task("dag skipped")
+                .unwrap()
+                .error
+                .unwrap()
+                .message,
+            "dependency dag failing did not succeed"
+        );
+        assert_eq!(run_count("dag skipped"), 0);
+        assert_eq!(run_count("dag skipped transitively"), 0);
+    }
+
+    #[test]
+    fn failed_tasks_are_retried() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        let retrying_task = |task_id: &str, parameters, retry_policy| {
+            let mut new_task_info = counting_task(task_id, parameters, &[]);
+            new_task_info.task_definition.retry_policy = retry_policy;
+            new_task_info
+        };
+        let three_attempts = RetryPolicy {
+            max_attempts: 3,
+            retry_backoff: RetryBackoff::Exponential {
+                initial_delay_seconds: 0.05,
+                multiplier: 2.0,
+                max_delay_seconds: 1.0,
+                jitter: true,
+            },
+            retry_on: Vec::new(),
+        };
+        registry
+            .create_task(&retrying_task(
+                "retry flaky",
+                json!({"id": "retry flaky", "fail_runs": 2}),
+                three_attempts.clone(),
+            ))
+            .unwrap();
+        registry
+            .create_task(&retrying_task(
+                "retry broken",
+                json!({"id": "retry broken", "fail": true}),
+                three_attempts.clone(),
+            ))
+            .unwrap();
+        registry
+            .create_task(&retrying_task(
+                "retry not retryable",
+                json!({"id": "retry not retryable", "fail": true}),
+                RetryPolicy {
+                    retry_on: vec!["timed_out".to_string()],
+                    ..three_attempts
+                },
+            ))
+            .unwrap();
+        let task_ids = ["retry flaky", "retry broken", "retry not retryable"];
+        run_until_finished(&mut control_loop, &registry, &task_ids);
+
+        let flaky = registry.get_task("retry flaky").unwrap();
+        assert_eq!(flaky.status, TaskStatus::SUCCESS);
+        assert_eq!(flaky.attempt, 3);
+        assert_eq!(
+            flaky
+                .attempt_errors
+                .iter()
+                .map(|x| x.attempt)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(flaky.attempt_errors[0].kind, "other");
+        assert_eq!(flaky.attempt_errors[0].message, "failing on purpose");
+
+        assert_eq!(flaky.error, None);
+
+        let broken = registry.get_task("retry broken").unwrap();
+        assert_eq!(broken.status, TaskStatus::FAILED);
+        assert_eq!(broken.attempt_errors.len(), 3);
+        let error = broken.error.unwrap();
+        assert_eq!(error.kind, "other");
+        assert_eq!(error.message, "failing on purpose");
+        assert_eq!(error.exit_code, None);
+        assert_eq!(run_count("retry broken"), 3);
+
+        let not_retryable = registry.get_task("retry not retryable").unwrap();
+        assert_eq!(not_retryable.status, TaskStatus::FAILED);
+        assert_eq!(run_count("retry not retryable"), 1);
+    }
+
+    #[test]
+    fn extreme_backoffs_do_not_stop_the_loop() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        // Stored without going through the API
+        for (task_id, retry_backoff) in [
+            (
+                "backoff exploding",
+                RetryBackoff::Exponential {
+                    initial_delay_seconds: 1.0,
+                    multiplier: 1e300,
+                    max_delay_seconds: 1e300,
+                    jitter: false,
+                },
+            ),
+            (
+                "backoff huge",
+                RetryBackoff::Fixed {
+                    delay_seconds: 1e300,
+                },
+            ),
+        ] {
+            let mut new_task_info =
+                counting_task(task_id, json!({"id": task_id, "fail": true}), &[]);
+            new_task_info.task_definition.retry_policy = RetryPolicy {
+                max_attempts: 3,
+                retry_backoff,
+                retry_on: Vec::new(),
+            };
+            registry.create_task(&new_task_info).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        // The exponential backoff blows up from the second retry on
+        for (task_id, failed_attempts) in [("backoff exploding", 2), ("backoff huge", 1)] {
+            while registry.get_task(task_id).unwrap().attempt_errors.len() < failed_attempts {
+                assert!(Instant::now() < deadline, "task did not fail in time");
+                control_loop.run_once();
+                thread::sleep(Duration::from_millis(10));
+            }
+            // Retried a year later at most
+            let task_state = registry.get_task(task_id).unwrap();
+            assert_eq!(task_state.status, TaskStatus::PENDING);
+            let next_attempt_at = task_state.next_attempt_at.unwrap();
+            assert!(next_attempt_at > timestamp_now() + chrono::Duration::days(364));
+            assert!(next_attempt_at <= timestamp_now() + chrono::Duration::days(365));
+        }
+    }
+
+    #[test]
+    fn hung_tasks_time_out() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        let mut hung = counting_task(
+            "timeout hung",
+            json!({"id": "timeout hung", "hang_ms": 1000}),
+            &[],
+        );
+        hung.task_definition.timeout_seconds = Some(0.2);
+        hung.task_definition.retry_policy = RetryPolicy {
+            max_attempts: 2,
+            ..Default::default()
+        };
+        registry.create_task(&hung).unwrap();
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: "timeout shell".to_string(),
+                task_definition: TaskDefinition {
+                    kind: "shell_command".to_string(),
+                    parameters: json!({"program": "sleep", "args": ["10"]}),
+                    timeout_seconds: Some(0.2),
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        let mut fast = counting_task("timeout fast", json!({"id": "timeout fast"}), &[]);
+        fast.task_definition.timeout_seconds = Some(5.0);
+        registry.create_task(&fast).unwrap();
+
+        let started = Instant::now();
+        run_until_finished(
+            &mut control_loop,
+            &registry,
+            &["timeout hung", "timeout shell", "timeout fast"],
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let hung = registry.get_task("timeout hung").unwrap();
+        assert_eq!(hung.status, TaskStatus::TIMED_OUT);
+        assert_eq!(hung.attempt, 2);
+        assert_eq!(hung.attempt_errors[0].kind, "timed_out");
+        assert_eq!(hung.error.unwrap().kind, "timed_out");
+        let shell = registry.get_task("timeout shell").unwrap();
+        assert_eq!(shell.status, TaskStatus::TIMED_OUT);
+        assert_eq!(
+            registry.get_task("timeout fast").unwrap().status,
+            TaskStatus::SUCCESS
+        );
+
+        // The abandoned attempts finishing later must not change the outcome
+        thread::sleep(Duration::from_millis(1500));
+        control_loop.run_once();
+        assert_eq!(
+            registry.get_task("timeout hung").unwrap().status,
+            TaskStatus::TIMED_OUT
+        );
+    }
+
+    #[test]
+    fn timed_out_tasks_give_up_their_workers() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) = control_loop_with_counting_kind(
+            &registry,
+            ControlLoopConfig {
+                worker_count: 2,
+                ..Default::default()
+            },
+        );
+
+        let hung_ids = ["give up hung 1", "give up hung 2"];
+        for task_id in hung_ids {
+            let mut hung = counting_task(task_id, json!({"id": task_id, "hang_ms": 3000}), &[]);
+            hung.task_definition.timeout_seconds = Some(0.2);
+            registry.create_task(&hung).unwrap();
+        }
+        run_until_finished(&mut control_loop, &registry, &hung_ids);
+
+        // Both still hold their old workers, but each run takes 600ms on a
+        // worker of its own
+        let after_ids = ["give up after 1", "give up after 2"];
+        for task_id in after_ids {
+            registry
+                .create_task(&counting_task(
+                    task_id,
+                    json!({"id": task_id, "hang_ms": 500}),
+                    &[],
+                ))
+                .unwrap();
+        }
+        let started = Instant::now();
+        run_until_finished(&mut control_loop, &registry, &after_ids);
+        assert!(started.elapsed() < Duration::from_millis(1000));
+        assert_eq!(run_count("give up hung 1"), 0);
+        assert_eq!(control_loop.worker_count(), 2);
+    }
+
+    #[test]
+    fn tasks_time_out_before_running_is_recorded() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        let mut hung = counting_task(
+            "timeout unrecorded",
+            json!({"id": "timeout unrecorded", "hang_ms": 1000}),
+            &[],
+        );
+        hung.task_definition.timeout_seconds = Some(0.01);
+        registry.create_task(&hung).unwrap();
+        control_loop.dispatch_pending().unwrap();
+        // Started and timed out without `advance_running` noticing
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            registry.get_task("timeout unrecorded").unwrap().status,
+            TaskStatus::QUEUED
+        );
+        control_loop.stop_timed_out();
+
+        let task_state = registry.get_task("timeout unrecorded").unwrap();
+        assert_eq!(task_state.status, TaskStatus::TIMED_OUT);
+        assert!(task_state.started_at.is_some());
+        assert!(control_loop.in_flight.is_empty());
+    }
+
+    #[test]
+    fn results_wait_until_running_is_recorded() {
+        let registry = FlakyRegistry::new();
+        let (mut control_loop, _cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        registry
+            .create_task(&counting_task(
+                "flaky running",
+                json!({"id": "flaky running"}),
+                &[],
+            ))
+            .unwrap();
+        control_loop.dispatch_pending().unwrap();
+        // Finished before `advance_running` first looks
+        thread::sleep(Duration::from_millis(300));
+        *registry.fail_update.lock().unwrap() = Some(TaskStatus::RUNNING);
+        control_loop.advance_running();
+        assert_eq!(
+            registry.get_task("flaky running").unwrap().status,
+            TaskStatus::QUEUED
+        );
+        assert!(control_loop.in_flight.contains_key("flaky running"));
+
+        control_loop.advance_running();
+        let statuses: Vec<TaskStatus> = registry
+            .get_task_events("flaky running")
+            .unwrap()
+            .into_iter()
+            .map(|task_event| task_event.status)
+            .collect();
+        assert!(statuses.contains(&TaskStatus::RUNNING));
+        assert_eq!(statuses.last(), Some(&TaskStatus::SUCCESS));
+        assert!(control_loop.in_flight.is_empty());
+    }
+
+    #[test]
+    fn invalid_timeouts_fail_tasks() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        // Stored without going through the API
+        for (task_id, timeout_seconds) in [
+            ("timeout negative", -1.0),
+            ("timeout huge", 1e300),
+            ("timeout far off", 1e15),
+        ] {
+            let mut new_task_info = counting_task(task_id, json!({ "id": task_id }), &[]);
+            new_task_info.task_definition.timeout_seconds = Some(timeout_seconds);
+            registry.create_task(&new_task_info).unwrap();
+        }
+        let task_ids = ["timeout negative", "timeout huge", "timeout far off"];
+        run_until_finished(&mut control_loop, &registry, &task_ids);
+
+        for task_id in ["timeout negative", "timeout huge"] {
+            let task_state = registry.get_task(task_id).unwrap();
+            assert_eq!(task_state.status, TaskStatus::FAILED);
+            assert_eq!(task_state.error.unwrap().kind, "invalid_task");
+            assert_eq!(run_count(task_id), 0);
+        }
+        assert_eq!(
+            registry.get_task("timeout far off").unwrap().status,
+            TaskStatus::SUCCESS
+        );
+    }
+
+    #[test]
+    fn cancelled_tasks_stop() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: "cancel running".to_string(),
+                task_definition: TaskDefinition {
+                    kind: "shell_command".to_string(),
+                    parameters: json!({"program": "sleep", "args": ["10"]}),
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        registry
+            .create_task(&counting_task(
+                "cancel pending",
+                json!({"id": "cancel pending"}),
+                &["cancel running"],
+            ))
+            .unwrap();
+        registry
+            .create_task(&counting_task(
+                "cancel downstream",
+                json!({"id": "cancel downstream"}),
+                &["cancel pending"],
+            ))
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while registry
+            .get_task("cancel running")
+            .map(|x| x.status != TaskStatus::RUNNING)
+            .unwrap_or(true)
+        {
+            assert!(Instant::now() < deadline, "task did not start in time");
+            control_loop.run_once();
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        for task_id in ["cancel running", "cancel pending"] {
+            cancel_sender
+                .send(CancelTaskInfo {
+                    task_id: task_id.to_string(),
+                })
+                .unwrap();
+        }
+        let started = Instant::now();
+        run_until_finished(
+            &mut control_loop,
+            &registry,
+            &["cancel running", "cancel pending", "cancel downstream"],
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let status = |task_id| registry.get_task(task_id).unwrap().status;
+        assert_eq!(status("cancel running"), TaskStatus::CANCELLED);
+        assert_eq!(status("cancel pending"), TaskStatus::CANCELLED);
+        assert_eq!(status("cancel downstream"), TaskStatus::UPSTREAM_FAILED);
+        assert_eq!(run_count("cancel pending"), 0);
+
+        // The killed process reporting back must not change the outcome
+        thread::sleep(Duration::from_millis(200));
+        control_loop.run_once();
+        assert_eq!(status("cancel running"), TaskStatus::CANCELLED);
+    }
+
+    #[test]
+    fn failed_cancellations_are_retried() {
+        let registry = FlakyRegistry::new();
+        let (mut control_loop, cancel_sender) =
+            control_loop_with_counting_kind(&registry, ControlLoopConfig::default());
+
+        registry
+            .create_task(&counting_task(
+                "cancel flaky",
+                json!({"id": "cancel flaky", "hang_ms": 500}),
+                &[],
+            ))
+            .unwrap();
+        while registry.get_task("cancel flaky").unwrap().status != TaskStatus::RUNNING {
+            control_loop.run_once();
+            thread::sleep(Duration::from_millis(10));
+        }
+        *registry.fail_update.lock().unwrap() = Some(TaskStatus::CANCELLED);
+        cancel_sender
+            .send(CancelTaskInfo {
+                task_id: "cancel flaky".to_string(),
+            })
+            .unwrap();
+        control_loop.run_once();
+        assert_eq!(
+            registry.get_task("cancel flaky").unwrap().status,
+            TaskStatus::RUNNING
+        );
+        assert!(control_loop.in_flight.contains_key("cancel flaky"));
+
+        control_loop.run_once();
+        assert_eq!(
+            registry.get_task("cancel flaky").unwrap().status,
+            TaskStatus::CANCELLED
+        );
+        assert!(control_loop.in_flight.is_empty());
+    }
+
+    #[test]
+    fn cancelled_queued_tasks_never_run() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, cancel_sender) = control_loop_with_counting_kind(
+            &registry,
+            ControlLoopConfig {
+                worker_count: 1,
+                ..Default::default()
+            },
+        );
+
+        registry
+            .create_task(&counting_task(
+                "cancel busy",
+                json!({"id": "cancel busy", "hang_ms": 300}),
+                &[],
+            ))
+            .unwrap();
+        registry
+            .create_task(&counting_task(
+                "cancel queued",
+                json!({"id": "cancel queued"}),
+                &[],
+            ))
+            .unwrap();
+        // Both are handed to the pool, the second waits behind the first
+        control_loop.run_once();
+        assert_eq!(
+            registry.get_task("cancel queued").unwrap().status,
+            TaskStatus::QUEUED
+        );
+        cancel_sender
+            .send(CancelTaskInfo {
+                task_id: "cancel queued".to_string(),
+            })
+            .unwrap();
+        run_until_finished(
+            &mut control_loop,
+            &registry,
+            &["cancel busy", "cancel queued"],
+        );
+        // Gives the worker the chance to pick up the cancelled job
+        thread::sleep(Duration::from_millis(200));
+
+        let status = |task_id| registry.get_task(task_id).unwrap().status;
+        assert_eq!(status("cancel busy"), TaskStatus::SUCCESS);
+        assert_eq!(status("cancel queued"), TaskStatus::CANCELLED);
+        assert_eq!(run_count("cancel queued"), 0);
+    }
+
+    #[test]
+    fn orphans_are_recovered_on_startup() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        // Left behind by a runner that died while they were queued or running
+        let requeued_task = |task_id: &str, max_attempts| {
+            let mut new_task_info = counting_task(task_id, json!({ "id": task_id }), &[]);
+            new_task_info.task_definition.on_orphaned = Some(OrphanPolicy::Requeue);
+            new_task_info.task_definition.retry_policy.max_attempts = max_attempts;
+            new_task_info
+        };
+        for new_task_info in [
+            counting_task("orphan queued", json!({"id": "orphan queued"}), &[]),
+            counting_task("orphan running", json!({"id": "orphan running"}), &[]),
+            requeued_task("orphan requeued", 2),
+            // Its only attempt was the one lost
+            requeued_task("orphan out of attempts", 1),
+        ] {
+            registry.create_task(&new_task_info).unwrap();
+            registry.claim_task(&new_task_info.task_id).unwrap();
+        }
+        registry
+            .update_task_from_control_loop("orphan running", TaskStatus::RUNNING)
+            .unwrap();
+        registry
+            .update_task_from_control_loop("orphan requeued", TaskStatus::RUNNING)
+            .unwrap();
+
+        let (mut control_loop, _cancel_sender) = control_loop_with_counting_kind(
+            &registry,
+            ControlLoopConfig {
+                orphan_policy: OrphanPolicy::Fail,
+                ..Default::default()
+            },
+        );
+        for task_id in ["orphan queued", "orphan running"] {
+            let task_state = registry.get_task(task_id).unwrap();
+            assert_eq!(task_state.status, TaskStatus::FAILED);
+            assert_eq!(task_state.attempt_errors[0].kind, "orphaned");
+        }
+        assert_eq!(
+            registry.get_task("orphan requeued").unwrap().status,
+            TaskStatus::PENDING
+        );
+        let out_of_attempts = registry.get_task("orphan out of attempts").unwrap();
+        assert_eq!(out_of_attempts.status, TaskStatus::FAILED);
+        assert_eq!(out_of_attempts.error.unwrap().kind, "orphaned");
+
+        run_until_finished(&mut control_loop, &registry, &["orphan requeued"]);
+        let requeued = registry.get_task("orphan requeued").unwrap();
+        assert_eq!(requeued.status, TaskStatus::SUCCESS);
+        assert_eq!(requeued.attempt, 2);
+        assert_eq!(requeued.attempt_errors[0].kind, "orphaned");
+        assert_eq!(run_count("orphan queued"), 0);
+        assert_eq!(run_count("orphan out of attempts"), 0);
+    }
+
+    #[test]
+    fn workers_can_be_resized() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) = control_loop_with_counting_kind(
+            &registry,
+            ControlLoopConfig {
+                worker_count: 1,
+                ..Default::default()
+            },
+        );
+        assert_eq!(control_loop.worker_count(), 1);
+        control_loop
+            .resize_sender()
+            .send(ResizeWorkersInfo { worker_count: 4 })
+            .unwrap();
+
+        let task_ids: Vec<String> = (0..4).map(|i| format!("resized {i}")).collect();
+        for task_id in &task_ids {
+            registry
+                .create_task(&counting_task(
+                    task_id,
+                    json!({ "id": task_id, "hang_ms": 400 }),
+                    &[],
+                ))
+                .unwrap();
+        }
+        let task_ids: Vec<&str> = task_ids.iter().map(|x| x.as_str()).collect();
+        let started = Instant::now();
+        run_until_finished(&mut control_loop, &registry, &task_ids);
+        assert_eq!(control_loop.worker_count(), 4);
+        // One at a time would take at least two seconds
+        assert!(started.elapsed() < Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn panicking_tasks_fail() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", "test_table", TablePermanance::DropOnClose)
+                .unwrap();
+        let (mut control_loop, _cancel_sender) = control_loop_with_counting_kind(
+            &registry,
+            ControlLoopConfig {
+                worker_count: 1,
+                ..Default::default()
+            },
+        );
+
+        registry
+            .create_task(&counting_task(
+                "panic panicking",
+                json!({"id": "panic panicking", "panic": true}),
+                &[],
+            ))
+            .unwrap();
+        registry
+            .create_task(&counting_task(
+                "panic after",
+                json!({"id": "panic after"}),
+                &[],
+            ))
+            .unwrap();
+        run_until_finished(
+            &mut control_loop,
+            &registry,
+            &["panic panicking", "panic after"],
+        );
+
+        let panicking = registry.get_task("panic panicking").unwrap();
+        assert_eq!(panicking.status, TaskStatus::FAILED);
+        let error = panicking.error.unwrap();
+        assert_eq!(error.kind, "panic");
+        assert_eq!(
+            error.message,
+            "task panicked: counting task panic panicking panicked on purpose"
+        );
+        // The only worker survived to run the next task
+        assert_eq!(
+            registry.get_task("panic after").unwrap().status,
+            TaskStatus::SUCCESS
+        );
+    }
 }
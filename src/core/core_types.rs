@@ -1,93 +1,413 @@
 use std::fmt::Display;
-use std::fs::File;
-use std::io::prelude::*;
-use std::thread;
-use std::time::Duration;
 
+use chrono::{DateTime, SubsecRound, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::core::retry_policy::RetryPolicy;
+
+/// The current time at the millisecond precision the registries store.
+pub fn timestamp_now() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(3)
+}
+
 pub struct NewTaskInfo {
     pub task_id: String,
     pub task_definition: TaskDefinition,
 }
 
+pub struct CancelTaskInfo {
+    pub task_id: String,
+}
+
+pub struct ResizeWorkersInfo {
+    pub worker_count: usize,
+}
+
+#[allow(non_camel_case_types)]
 #[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum TaskStatus {
+    /// Waiting for its `run_at` time, after which it becomes `PENDING`.
+    SCHEDULED,
     PENDING,
+    /// Will never run because a task it depends on did not succeed.
+    UPSTREAM_FAILED,
+    /// Claimed by the control loop and handed to the threadpool, but not yet
+    /// picked up by a worker.
+    QUEUED,
     RUNNING,
     FAILED,
+    /// Ran for longer than its `timeout_seconds`.
+    TIMED_OUT,
+    CANCELLED,
     SUCCESS,
 }
 
+impl TaskStatus {
+    /// Whether the task has stopped for good and its status will not change.
+    pub fn is_finished(&self) -> bool {
+        match self {
+            TaskStatus::SCHEDULED
+            | TaskStatus::PENDING
+            | TaskStatus::QUEUED
+            | TaskStatus::RUNNING => false,
+            TaskStatus::UPSTREAM_FAILED
+            | TaskStatus::FAILED
+            | TaskStatus::TIMED_OUT
+            | TaskStatus::CANCELLED
+            | TaskStatus::SUCCESS => true,
+        }
+    }
+
+    /// The task state machine. A `SCHEDULED` task becomes `PENDING` once it
+    /// is due. A `PENDING` task is claimed into `QUEUED`,
+    /// picked up by a worker into `RUNNING` and ends in one of the finished
+    /// statuses, or goes back to `PENDING` to be retried. Finished tasks never
+    /// change again.
+    pub fn can_transition_to(&self, next: &TaskStatus) -> bool {
+        match self {
+            TaskStatus::SCHEDULED => {
+                matches!(next, TaskStatus::PENDING | TaskStatus::CANCELLED)
+            }
+            TaskStatus::PENDING => matches!(
+                next,
+                TaskStatus::QUEUED
+                    | TaskStatus::UPSTREAM_FAILED
+                    | TaskStatus::FAILED
+                    | TaskStatus::CANCELLED
+            ),
+            // Orphaned tasks go from `QUEUED` straight to `FAILED` or back to
+            // `PENDING`
+            TaskStatus::QUEUED => matches!(
+                next,
+                TaskStatus::RUNNING
+                    | TaskStatus::PENDING
+                    | TaskStatus::FAILED
+                    | TaskStatus::CANCELLED
+            ),
+            TaskStatus::RUNNING => matches!(
+                next,
+                TaskStatus::PENDING
+                    | TaskStatus::FAILED
+                    | TaskStatus::TIMED_OUT
+                    | TaskStatus::CANCELLED
+                    | TaskStatus::SUCCESS
+            ),
+            TaskStatus::UPSTREAM_FAILED
+            | TaskStatus::FAILED
+            | TaskStatus::TIMED_OUT
+            | TaskStatus::CANCELLED
+            | TaskStatus::SUCCESS => false,
+        }
+    }
+}
+
 impl std::str::FromStr for TaskStatus {
     type Err = ();
 
     fn from_str(input: &str) -> Result<TaskStatus, Self::Err> {
         match input {
+            "SCHEDULED" => Ok(TaskStatus::SCHEDULED),
             "PENDING" => Ok(TaskStatus::PENDING),
+            "UPSTREAM_FAILED" => Ok(TaskStatus::UPSTREAM_FAILED),
+            "QUEUED" => Ok(TaskStatus::QUEUED),
             "RUNNING" => Ok(TaskStatus::RUNNING),
             "FAILED" => Ok(TaskStatus::FAILED),
+            "TIMED_OUT" => Ok(TaskStatus::TIMED_OUT),
+            "CANCELLED" => Ok(TaskStatus::CANCELLED),
             "SUCCESS" => Ok(TaskStatus::SUCCESS),
             _ => Err(()),
         }
     }
 }
 
-impl std::string::ToString for TaskStatus {
-    fn to_string(&self) -> String {
-        match self {
-            TaskStatus::PENDING => "PENDING".to_string(),
-            TaskStatus::RUNNING => "RUNNING".to_string(),
-            TaskStatus::FAILED => "FAILED".to_string(),
-            TaskStatus::SUCCESS => "SUCCESS".to_string(),
+impl Display for TaskStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let status = match self {
+            TaskStatus::SCHEDULED => "SCHEDULED",
+            TaskStatus::PENDING => "PENDING",
+            TaskStatus::UPSTREAM_FAILED => "UPSTREAM_FAILED",
+            TaskStatus::QUEUED => "QUEUED",
+            TaskStatus::RUNNING => "RUNNING",
+            TaskStatus::FAILED => "FAILED",
+            TaskStatus::TIMED_OUT => "TIMED_OUT",
+            TaskStatus::CANCELLED => "CANCELLED",
+            TaskStatus::SUCCESS => "SUCCESS",
+        };
+        write!(f, "{status}")
+    }
+}
+
+/// What to do with a task found `QUEUED` or `RUNNING` when the control loop
+/// starts, which means the process that was running it died.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrphanPolicy {
+    /// Fail the task, recording that it was lost
+    #[default]
+    Fail,
+    /// Retry the task as if the attempt had failed with kind `orphaned`, so
+    /// its retry policy decides whether it runs again
+    Requeue,
+}
+
+impl std::str::FromStr for OrphanPolicy {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<OrphanPolicy, Self::Err> {
+        match input {
+            "fail" => Ok(OrphanPolicy::Fail),
+            "requeue" => Ok(OrphanPolicy::Requeue),
+            _ => Err(()),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+impl Display for OrphanPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let orphan_policy = match self {
+            OrphanPolicy::Fail => "fail",
+            OrphanPolicy::Requeue => "requeue",
+        };
+        write!(f, "{orphan_policy}")
+    }
+}
+
+/// What to run: the name of a registered task kind plus the parameters that
+/// kind understands. See `core::task_kinds`.
+///
+/// A task is only dispatched once every task in `depends_on` has succeeded.
+/// Dependencies may be submitted after the task that depends on them.
+/// Stored as JSON as the template of a schedule, see `core::schedule`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct TaskDefinition {
-    pub sleep_time_seconds: u16,
+    pub kind: String,
+    pub parameters: serde_json::Value,
+    pub depends_on: Vec<String>,
+    pub retry_policy: RetryPolicy,
+    /// Each attempt is stopped once it has run for this long.
+    pub timeout_seconds: Option<f64>,
+    /// Overrides the control loop's `OrphanPolicy` for this task.
+    pub on_orphaned: Option<OrphanPolicy>,
+    /// Lets a client resubmit the same task without getting a conflict.
+    pub idempotency_key: Option<String>,
+    /// Tasks with a higher priority are dispatched and run first. Defaults
+    /// to 0, and may be negative for work that can wait.
+    pub priority: i32,
+    /// Tasks in a queue share its concurrency limit, see
+    /// `ControlLoopConfig::queue_limits`. Tasks in no queue are only limited
+    /// by the number of workers.
+    pub queue: Option<String>,
+    /// The task is not dispatched before this time. Until then it is
+    /// `SCHEDULED` rather than `PENDING`.
+    pub run_at: Option<DateTime<Utc>>,
+}
+
+/// What a task run produced. Task kinds that do not spawn a process leave
+/// `exit_code` empty.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TaskOutput {
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Why one attempt of a task failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttemptError {
+    pub attempt: u32,
+    pub kind: String,
     pub message: String,
-    pub output_path: String,
+}
+
+/// Why a task ended up failed. Unlike `AttemptError`, which is kept for every
+/// failed attempt, this describes the failure that finished the task.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskError {
+    pub kind: String,
+    pub message: String,
+    /// Set when the task ran a process that exited unsuccessfully.
+    pub exit_code: Option<i32>,
+    pub backtrace: Option<String>,
+}
+
+impl TaskError {
+    pub fn new(kind: &str, message: &str) -> TaskError {
+        TaskError {
+            kind: kind.to_string(),
+            message: message.to_string(),
+            exit_code: None,
+            backtrace: None,
+        }
+    }
+}
+
+impl Display for TaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct TaskState {
     pub status: TaskStatus,
     pub name: String,
-    pub sleep_time_seconds: u16,
-    pub message: String,
-    pub output_path: String,
+    pub kind: String,
+    pub parameters: serde_json::Value,
+    pub depends_on: Vec<String>,
+    pub output: Option<TaskOutput>,
+    pub retry_policy: RetryPolicy,
+    /// Number of attempts started so far.
+    pub attempt: u32,
+    /// A `PENDING` task is not dispatched before this time.
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub attempt_errors: Vec<AttemptError>,
+    /// Set once the task has failed, see `TaskError`.
+    pub error: Option<TaskError>,
+    pub timeout_seconds: Option<f64>,
+    pub on_orphaned: Option<OrphanPolicy>,
+    pub idempotency_key: Option<String>,
+    pub priority: i32,
+    pub queue: Option<String>,
+    pub run_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    /// When the latest attempt was claimed by the control loop.
+    pub queued_at: Option<DateTime<Utc>>,
+    /// When a worker picked up the latest attempt.
+    pub started_at: Option<DateTime<Utc>>,
+    /// When the task reached a finished status.
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// What kind of thing happened to a task, see `TaskEvent`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskEventKind {
+    Created,
+    /// The control loop started a new attempt
+    Claimed,
+    StatusChanged,
+    AttemptFailed,
+    RetryScheduled,
+}
+
+impl std::str::FromStr for TaskEventKind {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<TaskEventKind, Self::Err> {
+        match input {
+            "created" => Ok(TaskEventKind::Created),
+            "claimed" => Ok(TaskEventKind::Claimed),
+            "status_changed" => Ok(TaskEventKind::StatusChanged),
+            "attempt_failed" => Ok(TaskEventKind::AttemptFailed),
+            "retry_scheduled" => Ok(TaskEventKind::RetryScheduled),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for TaskEventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = match self {
+            TaskEventKind::Created => "created",
+            TaskEventKind::Claimed => "claimed",
+            TaskEventKind::StatusChanged => "status_changed",
+            TaskEventKind::AttemptFailed => "attempt_failed",
+            TaskEventKind::RetryScheduled => "retry_scheduled",
+        };
+        write!(f, "{kind}")
+    }
+}
+
+/// One entry in the history of a task.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskEvent {
+    pub at: DateTime<Utc>,
+    pub kind: TaskEventKind,
+    /// The status of the task right after the event.
+    pub status: TaskStatus,
+    pub message: Option<String>,
 }
 
 impl Display for TaskState {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Task(name={}, status={})",
-            self.name,
-            self.status.to_string()
+            "Task(name={}, kind={}, status={})",
+            self.name, self.kind, self.status
         )
     }
 }
 
 impl TaskState {
     pub fn new(new_task_info: &NewTaskInfo) -> TaskState {
+        let now = timestamp_now();
+        let run_at = new_task_info.task_definition.run_at;
         TaskState {
-            status: TaskStatus::PENDING,
+            status: if run_at.is_some_and(|x| x > now) {
+                TaskStatus::SCHEDULED
+            } else {
+                TaskStatus::PENDING
+            },
             name: new_task_info.task_id.to_string(),
-            sleep_time_seconds: new_task_info.task_definition.sleep_time_seconds,
-            message: new_task_info.task_definition.message.to_string(),
-            output_path: new_task_info.task_definition.output_path.to_string(),
+            kind: new_task_info.task_definition.kind.to_string(),
+            parameters: new_task_info.task_definition.parameters.clone(),
+            depends_on: new_task_info.task_definition.depends_on.clone(),
+            output: None,
+            retry_policy: new_task_info.task_definition.retry_policy.clone(),
+            attempt: 0,
+            next_attempt_at: None,
+            attempt_errors: Vec::new(),
+            error: None,
+            timeout_seconds: new_task_info.task_definition.timeout_seconds,
+            on_orphaned: new_task_info.task_definition.on_orphaned,
+            idempotency_key: new_task_info.task_definition.idempotency_key.clone(),
+            priority: new_task_info.task_definition.priority,
+            queue: new_task_info.task_definition.queue.clone(),
+            run_at,
+            created_at: now,
+            queued_at: None,
+            started_at: None,
+            finished_at: None,
         }
     }
 
-    pub fn run(&self) -> Result<(), std::io::Error> {
-        thread::sleep(Duration::from_secs(self.sleep_time_seconds as u64));
-        println!("{}", &self.message);
-        // Write message to output_path
-        let mut file = File::create(&self.output_path)?;
-        file.write_all(self.message.as_bytes())?;
-        Ok(())
+    /// How long the latest attempt ran, once the task has finished.
+    pub fn duration(&self) -> Option<chrono::Duration> {
+        Some(self.finished_at? - self.started_at?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::core_types::TaskStatus;
+
+    #[test]
+    fn finished_tasks_never_change() {
+        let statuses = [
+            TaskStatus::SCHEDULED,
+            TaskStatus::PENDING,
+            TaskStatus::UPSTREAM_FAILED,
+            TaskStatus::QUEUED,
+            TaskStatus::RUNNING,
+            TaskStatus::FAILED,
+            TaskStatus::TIMED_OUT,
+            TaskStatus::CANCELLED,
+            TaskStatus::SUCCESS,
+        ];
+        for from in &statuses {
+            for to in &statuses {
+                if from.is_finished() || from == to {
+                    assert!(!from.can_transition_to(to), "{from} -> {to}");
+                }
+            }
+        }
+        assert!(TaskStatus::PENDING.can_transition_to(&TaskStatus::QUEUED));
+        assert!(TaskStatus::RUNNING.can_transition_to(&TaskStatus::PENDING));
+        assert!(!TaskStatus::PENDING.can_transition_to(&TaskStatus::SUCCESS));
+        assert!(!TaskStatus::QUEUED.can_transition_to(&TaskStatus::SUCCESS));
+        assert!(TaskStatus::SCHEDULED.can_transition_to(&TaskStatus::PENDING));
+        assert!(!TaskStatus::SCHEDULED.can_transition_to(&TaskStatus::QUEUED));
+        assert!(!TaskStatus::PENDING.can_transition_to(&TaskStatus::SCHEDULED));
     }
 }
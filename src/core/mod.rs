@@ -1 +1,6 @@
 pub mod core_types;
+pub mod retry_policy;
+pub mod schedule;
+pub mod shell_command_task;
+pub mod sleep_and_write_task;
+pub mod task_kinds;
@@ -0,0 +1,185 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Longest delay between attempts, a year. Longer delays are shortened to it.
+pub const MAX_RETRY_DELAY_SECONDS: f64 = 31_536_000.0;
+
+/// How long to wait before the next attempt of a failed task.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RetryBackoff {
+    Fixed {
+        delay_seconds: f64,
+    },
+    /// `initial_delay_seconds * multiplier ^ (attempt - 1)`, capped at
+    /// `max_delay_seconds`. With `jitter` the delay is scaled by a random
+    /// factor between 0.5 and 1 so that tasks failing together do not all
+    /// retry together.
+    Exponential {
+        initial_delay_seconds: f64,
+        #[serde(default = "default_multiplier")]
+        multiplier: f64,
+        #[serde(default = "default_max_delay_seconds")]
+        max_delay_seconds: f64,
+        #[serde(default = "default_jitter")]
+        jitter: bool,
+    },
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+fn default_max_delay_seconds() -> f64 {
+    3600.0
+}
+
+fn default_jitter() -> bool {
+    true
+}
+
+impl Default for RetryBackoff {
+    fn default() -> RetryBackoff {
+        RetryBackoff::Fixed { delay_seconds: 0.0 }
+    }
+}
+
+impl RetryBackoff {
+    /// Delay before the attempt following failed attempt number `attempt`
+    /// (counting from 1).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let seconds = match self {
+            RetryBackoff::Fixed { delay_seconds } => *delay_seconds,
+            RetryBackoff::Exponential {
+                initial_delay_seconds,
+                multiplier,
+                max_delay_seconds,
+                jitter,
+            } => {
+                let exponent = attempt.saturating_sub(1) as i32;
+                let delay =
+                    (initial_delay_seconds * multiplier.powi(exponent)).min(*max_delay_seconds);
+                if *jitter {
+                    delay * (0.5 + 0.5 * random_fraction())
+                } else {
+                    delay
+                }
+            }
+        };
+        // Not a number only if the fields are not numbers either
+        Duration::try_from_secs_f64(seconds.clamp(0.0, MAX_RETRY_DELAY_SECONDS)).unwrap_or_default()
+    }
+}
+
+/// A number in [0, 1). Only used for jitter, so the randomly keyed std hasher
+/// is good enough.
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// When and how often a failed task is attempted again. The default policy
+/// runs a task once and never retries it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub retry_backoff: RetryBackoff,
+    /// Failure kinds worth retrying, see `TaskFailure::kind`. Empty retries
+    /// every failure.
+    pub retry_on: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            retry_backoff: RetryBackoff::default(),
+            retry_on: Vec::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a task whose attempt number `attempt` failed with a failure of
+    /// kind `failure_kind` should be attempted again.
+    pub fn should_retry(&self, attempt: u32, failure_kind: &str) -> bool {
+        attempt < self.max_attempts
+            && (self.retry_on.is_empty() || self.retry_on.iter().any(|x| x == failure_kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::core::retry_policy::{RetryBackoff, RetryPolicy, MAX_RETRY_DELAY_SECONDS};
+
+    #[test]
+    fn retries_until_attempts_run_out() {
+        let retry_policy = RetryPolicy {
+            max_attempts: 3,
+            ..Default::default()
+        };
+        assert!(retry_policy.should_retry(1, "other"));
+        assert!(retry_policy.should_retry(2, "other"));
+        assert!(!retry_policy.should_retry(3, "other"));
+        assert!(!RetryPolicy::default().should_retry(1, "other"));
+    }
+
+    #[test]
+    fn retries_only_listed_failure_kinds() {
+        let retry_policy = RetryPolicy {
+            max_attempts: 3,
+            retry_on: vec!["timed_out".to_string(), "exit_code:75".to_string()],
+            ..Default::default()
+        };
+        assert!(retry_policy.should_retry(1, "timed_out"));
+        assert!(retry_policy.should_retry(1, "exit_code:75"));
+        assert!(!retry_policy.should_retry(1, "exit_code:1"));
+    }
+
+    #[test]
+    fn exponential_backoff_is_capped_and_jittered() {
+        let backoff = RetryBackoff::Exponential {
+            initial_delay_seconds: 1.0,
+            multiplier: 2.0,
+            max_delay_seconds: 5.0,
+            jitter: false,
+        };
+        assert_eq!(backoff.delay(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay(3), Duration::from_secs(4));
+        assert_eq!(backoff.delay(10), Duration::from_secs(5));
+
+        let jittered = RetryBackoff::Exponential {
+            initial_delay_seconds: 8.0,
+            multiplier: 2.0,
+            max_delay_seconds: 3600.0,
+            jitter: true,
+        };
+        for _ in 0..20 {
+            let delay = jittered.delay(1);
+            assert!(delay >= Duration::from_secs(4) && delay <= Duration::from_secs(8));
+        }
+    }
+
+    #[test]
+    fn extreme_backoffs_are_clamped() {
+        let max_delay = Duration::from_secs_f64(MAX_RETRY_DELAY_SECONDS);
+        let exploding = RetryBackoff::Exponential {
+            initial_delay_seconds: 1.0,
+            multiplier: 1e300,
+            max_delay_seconds: f64::INFINITY,
+            jitter: false,
+        };
+        assert_eq!(exploding.delay(5), max_delay);
+        let fixed = |delay_seconds| RetryBackoff::Fixed { delay_seconds };
+        assert_eq!(fixed(1e300).delay(1), max_delay);
+        assert_eq!(fixed(-1.0).delay(1), Duration::ZERO);
+        assert_eq!(fixed(f64::NAN).delay(1), Duration::ZERO);
+    }
+}
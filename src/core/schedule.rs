@@ -0,0 +1,339 @@
+use std::collections::BTreeSet;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::core::core_types::{timestamp_now, TaskDefinition};
+
+/// What to do about runs that came due while nothing was materialising them,
+/// e.g. while the runner was down or while `OverlapPolicy::Wait` held them.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedRunPolicy {
+    /// Only the latest of the missed runs is started
+    #[default]
+    Skip,
+    /// Every missed run is started, oldest first
+    CatchUp,
+}
+
+impl FromStr for MissedRunPolicy {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<MissedRunPolicy, Self::Err> {
+        match input {
+            "skip" => Ok(MissedRunPolicy::Skip),
+            "catch_up" => Ok(MissedRunPolicy::CatchUp),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for MissedRunPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let missed_run_policy = match self {
+            MissedRunPolicy::Skip => "skip",
+            MissedRunPolicy::CatchUp => "catch_up",
+        };
+        write!(f, "{missed_run_policy}")
+    }
+}
+
+/// What to do when a run comes due while the task of the previous run has not
+/// finished.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    /// The run is not started
+    #[default]
+    Skip,
+    /// The run is started once the previous one has finished, and counts as
+    /// missed until then
+    Wait,
+    /// The run is started alongside the previous one
+    Allow,
+}
+
+impl FromStr for OverlapPolicy {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<OverlapPolicy, Self::Err> {
+        match input {
+            "skip" => Ok(OverlapPolicy::Skip),
+            "wait" => Ok(OverlapPolicy::Wait),
+            "allow" => Ok(OverlapPolicy::Allow),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for OverlapPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let overlap_policy = match self {
+            OverlapPolicy::Skip => "skip",
+            OverlapPolicy::Wait => "wait",
+            OverlapPolicy::Allow => "allow",
+        };
+        write!(f, "{overlap_policy}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScheduleError {
+    InvalidCron { cron: String, message: String },
+    UnknownTimezone { timezone: String },
+}
+
+impl Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScheduleError::InvalidCron { cron, message } => {
+                write!(f, "invalid cron expression {cron}: {message}")
+            }
+            ScheduleError::UnknownTimezone { timezone } => write!(f, "unknown timezone {timezone}"),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+/// A cron expression evaluated in a timezone, so that a nightly run stays at
+/// the same local time across daylight saving changes.
+#[derive(Debug, Clone)]
+pub struct CronSchedule {
+    schedule: cron::Schedule,
+    timezone: Tz,
+}
+
+impl CronSchedule {
+    /// Takes the five fields of crontab, minute to day of week, where days
+    /// of the week are 0 to 7 from Sunday as in crontab, so that `1-5` is
+    /// Monday to Friday. Also takes the six or seven fields of the `cron`
+    /// crate, starting with seconds, where they are 1 to 7 from Sunday. Both
+    /// take `SUN` to `SAT`. `timezone` is an IANA name such as
+    /// `Europe/London`.
+    pub fn new(cron: &str, timezone: &str) -> Result<CronSchedule, ScheduleError> {
+        let invalid_cron = |message: String| ScheduleError::InvalidCron {
+            cron: cron.to_string(),
+            message,
+        };
+        let fields: Vec<&str> = cron.split_whitespace().collect();
+        let expression = if let [minutes, hours, days_of_month, months, days_of_week] = fields[..] {
+            let days_of_week = crontab_days_of_week(days_of_week).map_err(invalid_cron)?;
+            format!("0 {minutes} {hours} {days_of_month} {months} {days_of_week}")
+        } else {
+            cron.to_string()
+        };
+        let schedule = cron::Schedule::from_str(&expression)
+            .map_err(|error| invalid_cron(error.to_string()))?;
+        let timezone = Tz::from_str(timezone).map_err(|_| ScheduleError::UnknownTimezone {
+            timezone: timezone.to_string(),
+        })?;
+        Ok(CronSchedule { schedule, timezone })
+    }
+
+    /// Run times strictly after `after`, earliest first.
+    pub fn runs_after(&self, after: DateTime<Utc>) -> impl Iterator<Item = DateTime<Utc>> + '_ {
+        self.schedule
+            .after(&after.with_timezone(&self.timezone))
+            .map(|x| x.with_timezone(&Utc))
+    }
+}
+
+const DAYS_OF_WEEK: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+/// Rewrites a crontab day of week field, e.g. `1-5` or `0,6`, as the names of
+/// the days, since the `cron` crate numbers days differently.
+fn crontab_days_of_week(field: &str) -> Result<String, String> {
+    if field == "*" || field == "?" {
+        return Ok(field.to_string());
+    }
+    let mut days = BTreeSet::new();
+    for element in field.split(',') {
+        let (range, step) = match element.split_once('/') {
+            Some((range, step)) => match step.parse::<usize>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("invalid step in day of week {element}")),
+            },
+            None => (element, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((first, last)) => (crontab_day_of_week(first)?, crontab_day_of_week(last)?),
+            // A step from a single day runs to the end of the week
+            None if step > 1 => (crontab_day_of_week(range)?, 7),
+            None => {
+                let day = crontab_day_of_week(range)?;
+                (day, day)
+            }
+        };
+        if first > last {
+            return Err(format!("invalid range in day of week {element}"));
+        }
+        days.extend((first..=last).step_by(step).map(|day| day % 7));
+    }
+    let names: Vec<&str> = days.iter().map(|&day| DAYS_OF_WEEK[day]).collect();
+    Ok(names.join(","))
+}
+
+/// 0 to 7 from Sunday, or `SUN` to `SAT`.
+fn crontab_day_of_week(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(day) if day <= 7 => Ok(day),
+        _ => DAYS_OF_WEEK
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(value))
+            .ok_or_else(|| format!("invalid day of week {value}")),
+    }
+}
+
+pub struct NewScheduleInfo {
+    pub schedule_id: String,
+    pub schedule_definition: ScheduleDefinition,
+}
+
+/// Creates a task from `task_definition` every time `cron` comes due. See
+/// `CronSchedule` for the format of `cron` and `timezone`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScheduleDefinition {
+    pub cron: String,
+    pub timezone: String,
+    /// The template of each run. Its `run_at` and `idempotency_key` are not
+    /// used.
+    pub task_definition: TaskDefinition,
+    pub missed_runs: MissedRunPolicy,
+    pub overlap: OverlapPolicy,
+}
+
+impl ScheduleDefinition {
+    pub fn cron_schedule(&self) -> Result<CronSchedule, ScheduleError> {
+        CronSchedule::new(&self.cron, &self.timezone)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleState {
+    pub id: String,
+    pub schedule_definition: ScheduleDefinition,
+    pub created_at: DateTime<Utc>,
+    /// When the latest run handled was due, whether it was started or skipped.
+    /// Runs are due after this, or after `created_at` before the first.
+    pub last_run_at: Option<DateTime<Utc>>,
+    /// The task of the latest run that was started.
+    pub last_task_id: Option<String>,
+}
+
+impl ScheduleState {
+    pub fn new(new_schedule_info: &NewScheduleInfo) -> ScheduleState {
+        ScheduleState {
+            id: new_schedule_info.schedule_id.to_string(),
+            schedule_definition: new_schedule_info.schedule_definition.clone(),
+            created_at: timestamp_now(),
+            last_run_at: None,
+            last_task_id: None,
+        }
+    }
+
+    /// Derived from the time the run was due, so that materialising a run
+    /// twice, e.g. after a crash, conflicts instead of creating a second task.
+    pub fn task_id(&self, run_at: DateTime<Utc>) -> String {
+        format!("{}-{}", self.id, run_at.format("%Y%m%dT%H%M%SZ"))
+    }
+
+    /// Due runs, earliest first, from after `last_run_at` up to `now`.
+    pub fn due_runs<'a>(
+        &self,
+        cron_schedule: &'a CronSchedule,
+        now: DateTime<Utc>,
+    ) -> impl Iterator<Item = DateTime<Utc>> + 'a {
+        cron_schedule
+            .runs_after(self.last_run_at.unwrap_or(self.created_at))
+            .take_while(move |x| *x <= now)
+    }
+
+    /// The next run after `now`, unless the cron expression never fires again.
+    pub fn next_run_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let cron_schedule = self.schedule_definition.cron_schedule().ok()?;
+        let after = self.last_run_at.unwrap_or(self.created_at).max(now);
+        let mut runs = cron_schedule.runs_after(after);
+        runs.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Datelike, TimeZone, Utc, Weekday::*};
+
+    use crate::core::schedule::{CronSchedule, ScheduleError};
+
+    #[test]
+    fn cron_schedules_follow_their_timezone() {
+        // 02:30 in London is 01:30 UTC in summer and 02:30 UTC in winter
+        let cron_schedule = CronSchedule::new("30 2 * * *", "Europe/London").unwrap();
+        let after = Utc.with_ymd_and_hms(2024, 10, 25, 12, 0, 0).unwrap();
+        let runs: Vec<_> = cron_schedule.runs_after(after).take(3).collect();
+        assert_eq!(
+            runs,
+            vec![
+                Utc.with_ymd_and_hms(2024, 10, 26, 1, 30, 0).unwrap(),
+                Utc.with_ymd_and_hms(2024, 10, 27, 2, 30, 0).unwrap(),
+                Utc.with_ymd_and_hms(2024, 10, 28, 2, 30, 0).unwrap(),
+            ]
+        );
+
+        // Seconds may be given too
+        let cron_schedule = CronSchedule::new("*/10 * * * * *", "UTC").unwrap();
+        let runs: Vec<_> = cron_schedule.runs_after(after).take(2).collect();
+        assert_eq!(runs[1] - runs[0], chrono::Duration::seconds(10));
+    }
+
+    #[test]
+    fn crontab_days_of_week_count_from_sunday() {
+        let runs = |cron: &str| {
+            let cron_schedule = CronSchedule::new(cron, "UTC").unwrap();
+            // A Sunday
+            let after = Utc.with_ymd_and_hms(2024, 6, 2, 12, 0, 0).unwrap();
+            cron_schedule
+                .runs_after(after)
+                .take(7)
+                .map(|x| x.weekday())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(runs("0 9 * * 1-5"), vec![Mon, Tue, Wed, Thu, Fri, Mon, Tue]);
+        assert_eq!(runs("0 9 * * MON-FRI"), runs("0 9 * * 1-5"));
+        assert_eq!(runs("0 9 * * 0"), vec![Sun; 7]);
+        assert_eq!(runs("0 9 * * 7"), vec![Sun; 7]);
+        assert_eq!(runs("0 9 * * 5-7"), vec![Fri, Sat, Sun, Fri, Sat, Sun, Fri]);
+        assert_eq!(runs("0 9 * * */2"), vec![Tue, Thu, Sat, Sun, Tue, Thu, Sat]);
+        assert_eq!(runs("0 9 * * 0,6"), vec![Sat, Sun, Sat, Sun, Sat, Sun, Sat]);
+        // Fields of the cron crate keep its numbering
+        assert_eq!(runs("0 0 9 * * 2"), vec![Mon; 7]);
+
+        for cron in ["0 9 * * 8", "0 9 * * 5-1", "0 9 * * 1/0", "0 9 * * monday"] {
+            assert!(
+                matches!(
+                    CronSchedule::new(cron, "UTC"),
+                    Err(ScheduleError::InvalidCron { .. })
+                ),
+                "{cron}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_cron_schedules() {
+        assert!(matches!(
+            CronSchedule::new("every day", "UTC"),
+            Err(ScheduleError::InvalidCron { .. })
+        ));
+        assert_eq!(
+            CronSchedule::new("0 * * * *", "Mars/Olympus_Mons").unwrap_err(),
+            ScheduleError::UnknownTimezone {
+                timezone: "Mars/Olympus_Mons".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::core_types::TaskOutput;
+use crate::core::task_kinds::{Task, TaskContext, TaskFailure};
+
+/// How often a running process is checked for exit and for whether it should
+/// be killed.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Runs an external program and captures its exit code, stdout and stderr.
+/// A non-zero exit fails the task. The program, and anything it started, is
+/// killed when the task times out or is stopped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShellCommandTask {
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub working_directory: Option<String>,
+}
+
+impl ShellCommandTask {
+    pub const KIND: &'static str = "shell_command";
+
+    pub fn from_parameters(
+        parameters: &serde_json::Value,
+    ) -> Result<Box<dyn Task>, serde_json::Error> {
+        let task = ShellCommandTask::deserialize(parameters)?;
+        Ok(Box::new(task))
+    }
+}
+
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            // Whatever was read before an error is still worth keeping
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).to_string()
+    })
+}
+
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    // The child leads its own process group, see `ShellCommandTask::run`
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+impl Task for ShellCommandTask {
+    fn run(&self, context: &TaskContext) -> Result<TaskOutput, TaskFailure> {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .envs(&self.env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(working_directory) = &self.working_directory {
+            command.current_dir(working_directory);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+        let mut child = command.spawn()?;
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+
+        let exit_status = loop {
+            if let Some(exit_status) = child.try_wait()? {
+                break Some(exit_status);
+            }
+            if context.should_stop() {
+                kill(&mut child);
+                child.wait()?;
+                break None;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        let output = TaskOutput {
+            exit_code: exit_status.and_then(|x| x.code()),
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        };
+        match exit_status {
+            Some(exit_status) if exit_status.success() => Ok(output),
+            Some(exit_status) => Err(TaskFailure {
+                error: std::io::Error::other(format!(
+                    "{} exited with {}",
+                    self.program, exit_status
+                )),
+                output,
+            }),
+            None => Err(TaskFailure {
+                error: context.stop_error(),
+                output,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::core_types::TaskOutput;
+    use crate::core::shell_command_task::ShellCommandTask;
+    use crate::core::task_kinds::{Task, TaskContext};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn shell(script: &str) -> ShellCommandTask {
+        ShellCommandTask {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            env: HashMap::new(),
+            working_directory: None,
+        }
+    }
+
+    #[test]
+    fn captures_output_of_successful_command() {
+        let mut task = shell("echo \"$GREETING\"; echo oops >&2");
+        task.env.insert("GREETING".to_string(), "hello".to_string());
+        let output = task.run(&TaskContext::default()).unwrap();
+        assert_eq!(
+            output,
+            TaskOutput {
+                exit_code: Some(0),
+                stdout: "hello\n".to_string(),
+                stderr: "oops\n".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn non_zero_exit_fails_with_output() {
+        let failure = shell("echo partial; exit 3")
+            .run(&TaskContext::default())
+            .unwrap_err();
+        assert_eq!(failure.output.exit_code, Some(3));
+        assert_eq!(failure.output.stdout, "partial\n");
+    }
+
+    #[test]
+    fn runs_in_working_directory() {
+        let mut task = shell("pwd");
+        task.working_directory = Some("/".to_string());
+        assert_eq!(task.run(&TaskContext::default()).unwrap().stdout, "/\n");
+    }
+
+    #[test]
+    fn missing_program_fails() {
+        let mut task = shell("");
+        task.program = "this-program-does-not-exist".to_string();
+        let failure = task.run(&TaskContext::default()).unwrap_err();
+        assert_eq!(failure.error.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn killed_at_timeout() {
+        let context = TaskContext::new(Arc::default(), Some(Duration::from_millis(200)));
+        let started = Instant::now();
+        let failure = shell("echo started; sleep 10; echo finished")
+            .run(&context)
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(failure.error.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(failure.output.stdout, "started\n");
+    }
+}
@@ -0,0 +1,38 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::core_types::TaskOutput;
+use crate::core::task_kinds::{Task, TaskContext, TaskFailure};
+
+/// Sleeps, prints `message` and then writes it to `output_path`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SleepAndWriteTask {
+    pub sleep_time_seconds: u16,
+    pub message: String,
+    pub output_path: String,
+}
+
+impl SleepAndWriteTask {
+    pub const KIND: &'static str = "sleep_and_write";
+
+    pub fn from_parameters(
+        parameters: &serde_json::Value,
+    ) -> Result<Box<dyn Task>, serde_json::Error> {
+        let task = SleepAndWriteTask::deserialize(parameters)?;
+        Ok(Box::new(task))
+    }
+}
+
+impl Task for SleepAndWriteTask {
+    fn run(&self, context: &TaskContext) -> Result<TaskOutput, TaskFailure> {
+        context.sleep(Duration::from_secs(self.sleep_time_seconds as u64))?;
+        println!("{}", &self.message);
+        // Write message to output_path
+        let mut file = File::create(&self.output_path)?;
+        file.write_all(self.message.as_bytes())?;
+        Ok(TaskOutput::default())
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::core::core_types::{TaskError, TaskOutput};
+use crate::core::shell_command_task::ShellCommandTask;
+use crate::core::sleep_and_write_task::SleepAndWriteTask;
+
+/// A failed run, along with whatever output was captured before it failed.
+#[derive(Debug)]
+pub struct TaskFailure {
+    pub error: std::io::Error,
+    pub output: TaskOutput,
+}
+
+impl From<std::io::Error> for TaskFailure {
+    fn from(error: std::io::Error) -> TaskFailure {
+        TaskFailure {
+            error,
+            output: TaskOutput::default(),
+        }
+    }
+}
+
+/// The error of a task that panicked instead of returning.
+#[derive(Debug)]
+struct TaskPanic {
+    message: String,
+}
+
+impl fmt::Display for TaskPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "task panicked: {}", self.message)
+    }
+}
+
+impl std::error::Error for TaskPanic {}
+
+impl TaskFailure {
+    pub fn from_panic(message: &str) -> TaskFailure {
+        std::io::Error::other(TaskPanic {
+            message: message.to_string(),
+        })
+        .into()
+    }
+
+    /// A short name for the failure that `RetryPolicy::retry_on` can match:
+    /// `exit_code:<code>` for a process that exited unsuccessfully, `panic`
+    /// for a task that panicked, otherwise the snake_case I/O error kind,
+    /// e.g. `timed_out` or `not_found`.
+    pub fn kind(&self) -> String {
+        let panicked = self
+            .error
+            .get_ref()
+            .is_some_and(|error| error.is::<TaskPanic>());
+        match self.output.exit_code {
+            Some(exit_code) if exit_code != 0 => format!("exit_code:{exit_code}"),
+            _ if panicked => "panic".to_string(),
+            _ => {
+                let mut kind = String::new();
+                for (i, c) in format!("{:?}", self.error.kind()).chars().enumerate() {
+                    if c.is_uppercase() && i > 0 {
+                        kind.push('_');
+                    }
+                    kind.push(c.to_ascii_lowercase());
+                }
+                kind
+            }
+        }
+    }
+
+    pub fn task_error(&self) -> TaskError {
+        TaskError {
+            exit_code: self.output.exit_code.filter(|x| *x != 0),
+            ..TaskError::new(&self.kind(), &self.error.to_string())
+        }
+    }
+}
+
+/// How often `TaskContext::sleep` wakes up to check whether to stop.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Handed to a running task so that it can tell when it should give up: either
+/// its timeout has passed or it has been cancelled. Task kinds
+/// that run for a long time should check `should_stop` regularly, a task that
+/// never checks it keeps its worker busy until it returns.
+#[derive(Debug, Clone, Default)]
+pub struct TaskContext {
+    stop_requested: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl TaskContext {
+    pub fn new(stop_requested: Arc<AtomicBool>, timeout: Option<Duration>) -> TaskContext {
+        TaskContext {
+            stop_requested,
+            // Too far out to ever pass
+            deadline: timeout.and_then(|timeout| Instant::now().checked_add(timeout)),
+        }
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    pub fn should_stop(&self) -> bool {
+        self.stop_requested.load(Ordering::SeqCst) || self.timed_out()
+    }
+
+    /// The error a task should fail with after stopping early.
+    pub fn stop_error(&self) -> std::io::Error {
+        if self.timed_out() {
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "task timed out")
+        } else {
+            std::io::Error::new(std::io::ErrorKind::Interrupted, "task was cancelled")
+        }
+    }
+
+    /// Sleeps for `duration`, or fails with `stop_error` as soon as the task
+    /// should stop.
+    pub fn sleep(&self, duration: Duration) -> Result<(), std::io::Error> {
+        let wake_at = Instant::now() + duration;
+        loop {
+            if self.should_stop() {
+                return Err(self.stop_error());
+            }
+            let now = Instant::now();
+            if now >= wake_at {
+                return Ok(());
+            }
+            thread::sleep(STOP_POLL_INTERVAL.min(wake_at - now));
+        }
+    }
+}
+
+/// A unit of work built from a task definition and run on a threadpool worker.
+pub trait Task: Send {
+    fn run(&self, context: &TaskContext) -> Result<TaskOutput, TaskFailure>;
+}
+
+/// Builds a runnable task from the kind-specific parameters of a definition.
+pub type TaskFactory = fn(&serde_json::Value) -> Result<Box<dyn Task>, serde_json::Error>;
+
+#[derive(Debug, Clone)]
+pub enum TaskKindError {
+    UnknownKind { kind: String },
+    InvalidParameters { kind: String, message: String },
+}
+
+impl fmt::Display for TaskKindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TaskKindError::UnknownKind { kind } => write!(f, "unknown task kind {kind}"),
+            TaskKindError::InvalidParameters { kind, message } => {
+                write!(f, "invalid parameters for task kind {kind}: {message}")
+            }
+        }
+    }
+}
+
+/// Named task kinds that the control loop knows how to run.
+#[derive(Clone, Default)]
+pub struct TaskKindRegistry {
+    factories: HashMap<String, TaskFactory>,
+}
+
+impl TaskKindRegistry {
+    pub fn new() -> TaskKindRegistry {
+        TaskKindRegistry::default()
+    }
+
+    pub fn with_builtin_kinds() -> TaskKindRegistry {
+        let mut task_kinds = TaskKindRegistry::new();
+        task_kinds.register(SleepAndWriteTask::KIND, SleepAndWriteTask::from_parameters);
+        task_kinds.register(ShellCommandTask::KIND, ShellCommandTask::from_parameters);
+        task_kinds
+    }
+
+    pub fn register(&mut self, kind: &str, factory: TaskFactory) {
+        self.factories.insert(kind.to_string(), factory);
+    }
+
+    pub fn contains(&self, kind: &str) -> bool {
+        self.factories.contains_key(kind)
+    }
+
+    pub fn create(
+        &self,
+        kind: &str,
+        parameters: &serde_json::Value,
+    ) -> Result<Box<dyn Task>, TaskKindError> {
+        let factory = self
+            .factories
+            .get(kind)
+            .ok_or_else(|| TaskKindError::UnknownKind {
+                kind: kind.to_string(),
+            })?;
+        factory(parameters).map_err(|error| TaskKindError::InvalidParameters {
+            kind: kind.to_string(),
+            message: error.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::core_types::TaskOutput;
+    use crate::core::task_kinds::{TaskContext, TaskFailure, TaskKindError, TaskKindRegistry};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn unknown_kind_is_rejected() {
+        let task_kinds = TaskKindRegistry::with_builtin_kinds();
+        let result = task_kinds.create("does_not_exist", &json!({}));
+        assert!(matches!(result, Err(TaskKindError::UnknownKind { .. })));
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        let task_kinds = TaskKindRegistry::with_builtin_kinds();
+        let result = task_kinds.create("sleep_and_write", &json!({"message": 3}));
+        assert!(matches!(
+            result,
+            Err(TaskKindError::InvalidParameters { .. })
+        ));
+    }
+
+    #[test]
+    fn failure_kinds() {
+        let timed_out: TaskFailure = std::io::Error::from(std::io::ErrorKind::TimedOut).into();
+        assert_eq!(timed_out.kind(), "timed_out");
+        let exited = TaskFailure {
+            error: std::io::Error::other("exited"),
+            output: TaskOutput {
+                exit_code: Some(75),
+                ..Default::default()
+            },
+        };
+        assert_eq!(exited.kind(), "exit_code:75");
+        assert_eq!(exited.task_error().exit_code, Some(75));
+        assert_eq!(exited.task_error().message, "exited");
+        let panicked = TaskFailure::from_panic("oops");
+        assert_eq!(panicked.kind(), "panic");
+        assert_eq!(panicked.task_error().message, "task panicked: oops");
+    }
+
+    #[test]
+    fn builtin_sleep_and_write_runs() {
+        let output_path = std::env::temp_dir().join("task_kinds_sleep_and_write.txt");
+        let task_kinds = TaskKindRegistry::with_builtin_kinds();
+        let task = task_kinds
+            .create(
+                "sleep_and_write",
+                &json!({
+                    "sleep_time_seconds": 0,
+                    "message": "hello",
+                    "output_path": output_path.to_str().unwrap(),
+                }),
+            )
+            .unwrap();
+        task.run(&TaskContext::default()).unwrap();
+        assert_eq!(std::fs::read_to_string(&output_path).unwrap(), "hello");
+        std::fs::remove_file(output_path).unwrap();
+    }
+
+    #[test]
+    fn sleep_stops_at_timeout() {
+        let context = TaskContext::new(Arc::default(), Some(Duration::from_millis(100)));
+        let started = Instant::now();
+        let error = context.sleep(Duration::from_secs(10)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn sleep_stops_when_requested() {
+        let stop_requested = Arc::new(AtomicBool::new(false));
+        let context = TaskContext::new(stop_requested.clone(), None);
+        assert!(context.sleep(Duration::from_millis(10)).is_ok());
+        stop_requested.store(true, Ordering::SeqCst);
+        let error = context.sleep(Duration::from_secs(10)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Interrupted);
+    }
+}
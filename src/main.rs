@@ -1,36 +1,123 @@
 use actix_web::web::Data;
 use actix_web::{get, web, App, HttpServer, Responder};
-use std::sync::mpsc;
-use task_runner::control::control_api::{add_task, get_task, ControlApi};
-use task_runner::control::control_loop::ControlLoop;
-use task_runner::core::core_types::NewTaskInfo;
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc};
+use task_runner::control::control_api::{
+    add_schedule, add_task, cancel_task, delete_schedule, get_schedule, get_task, get_task_events,
+    list_schedules, list_tasks, resize_workers, ControlApi,
+};
+use task_runner::control::control_loop::{ControlLoop, ControlLoopConfig};
+use task_runner::core::core_types::{CancelTaskInfo, OrphanPolicy, ResizeWorkersInfo};
+use task_runner::core::task_kinds::TaskKindRegistry;
 use task_runner::registry::task_registry::TaskRegistry;
+use task_runner::registry::task_registry_in_memory::TaskRegistryInMemory;
 use task_runner::registry::task_registry_sqlite::{TablePermanance, TaskRegistrySqlite};
 
 const DATABASE_NAME: &str = "test.db";
+const TABLE_NAME: &str = "test_table";
 
 #[get("/hello/{name}")]
 async fn greet(name: web::Path<String>) -> impl Responder {
     format!("Hello {name}!")
 }
 
-fn registry_factory() -> Box<dyn TaskRegistry> {
-    Box::new(TaskRegistrySqlite::new(
-        DATABASE_NAME,
-        "test_table",
-        TablePermanance::DropOnClose,
-    ))
+/// Run with `--in-memory` to keep tasks in memory instead of in `DATABASE_NAME`.
+fn use_in_memory_registry() -> bool {
+    std::env::args().any(|x| x == "--in-memory")
+}
+
+/// Run with `--migrate` to bring `DATABASE_NAME` up to the current schema
+/// version and exit without starting the runner.
+fn migrate_only() -> bool {
+    std::env::args().any(|x| x == "--migrate")
+}
+
+/// Run with `--workers <count>` to run that many tasks at once. Can be changed
+/// while running through `PUT /admin/workers`.
+fn worker_count() -> Option<usize> {
+    let args: Vec<String> = std::env::args().collect();
+    let position = args.iter().position(|x| x == "--workers")?;
+    match args.get(position + 1).map(|x| x.parse::<usize>()) {
+        Some(Ok(worker_count)) if worker_count > 0 => Some(worker_count),
+        _ => {
+            println!("--workers must be followed by a positive number");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run with `--orphan-policy requeue` to retry the tasks a previous run left
+/// `QUEUED` or `RUNNING`, instead of failing them.
+fn orphan_policy() -> Option<OrphanPolicy> {
+    let args: Vec<String> = std::env::args().collect();
+    let position = args.iter().position(|x| x == "--orphan-policy")?;
+    match args.get(position + 1).map(|x| x.parse::<OrphanPolicy>()) {
+        Some(Ok(orphan_policy)) => Some(orphan_policy),
+        _ => {
+            println!("--orphan-policy must be followed by fail or requeue");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run with `--queue <name>=<limit>`, once per queue, to run at most that many
+/// tasks of the queue at once, e.g. `--queue io=4 --queue migrations=1`.
+fn queue_limits() -> HashMap<String, usize> {
+    let args: Vec<String> = std::env::args().collect();
+    let mut queue_limits = HashMap::new();
+    for (position, _) in args.iter().enumerate().filter(|(_, x)| *x == "--queue") {
+        let queue_limit = args.get(position + 1).and_then(|x| x.split_once('='));
+        match queue_limit.map(|(queue, limit)| (queue, limit.parse::<usize>())) {
+            Some((queue, Ok(limit))) if !queue.is_empty() && limit > 0 => {
+                queue_limits.insert(queue.to_string(), limit);
+            }
+            _ => {
+                println!("--queue must be followed by <name>=<positive number>");
+                std::process::exit(1);
+            }
+        }
+    }
+    queue_limits
+}
+
+/// Opened once and shared by the API workers and the control loop.
+fn open_registry() -> Arc<dyn TaskRegistry> {
+    if use_in_memory_registry() {
+        Arc::new(TaskRegistryInMemory::new())
+    } else {
+        Arc::new(
+            TaskRegistrySqlite::new(DATABASE_NAME, TABLE_NAME, TablePermanance::Keep)
+                .expect("could not open the task registry"),
+        )
+    }
 }
 
 #[actix_web::main]
-async fn server_main(sender: mpsc::Sender<NewTaskInfo>) -> std::io::Result<()> {
+async fn server_main(
+    cancel_sender: mpsc::Sender<CancelTaskInfo>,
+    resize_sender: mpsc::Sender<ResizeWorkersInfo>,
+    registry: Arc<dyn TaskRegistry>,
+) -> std::io::Result<()> {
     HttpServer::new(move || {
-        let control_api = ControlApi::new(sender.clone(), registry_factory);
+        let control_api = ControlApi::new(
+            cancel_sender.clone(),
+            resize_sender.clone(),
+            registry.clone(),
+            TaskKindRegistry::with_builtin_kinds(),
+        );
         let data = Data::new(control_api);
         App::new()
             .app_data(data.clone())
             .service(add_task)
+            .service(list_tasks)
+            .service(get_task_events)
             .service(get_task)
+            .service(cancel_task)
+            .service(add_schedule)
+            .service(list_schedules)
+            .service(get_schedule)
+            .service(delete_schedule)
+            .service(resize_workers)
     })
     .bind(("localhost", 8080))?
     .run()
@@ -38,16 +125,40 @@ async fn server_main(sender: mpsc::Sender<NewTaskInfo>) -> std::io::Result<()> {
 }
 
 fn main() {
-    let (sender, receiver) = mpsc::channel::<NewTaskInfo>();
+    if migrate_only() {
+        match TaskRegistrySqlite::migrate(DATABASE_NAME, TABLE_NAME) {
+            Ok(version) => println!("Task registry is at schema version {version}"),
+            Err(error) => {
+                println!("Could not migrate the task registry: {error}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let (cancel_sender, cancel_receiver) = mpsc::channel::<CancelTaskInfo>();
 
+    let registry = open_registry();
+    let mut config = ControlLoopConfig::default();
+    if let Some(worker_count) = worker_count() {
+        config.worker_count = worker_count;
+    }
+    if let Some(orphan_policy) = orphan_policy() {
+        config.orphan_policy = orphan_policy;
+    }
+    config.queue_limits = queue_limits();
+    let mut control_loop = ControlLoop::new(
+        registry.as_ref(),
+        TaskKindRegistry::with_builtin_kinds(),
+        cancel_receiver,
+        config,
+    );
     // Run server in background thread
+    let server_registry = registry.clone();
+    let resize_sender = control_loop.resize_sender();
     let server_handle = std::thread::spawn(move || {
-        server_main(sender.clone()).unwrap();
+        server_main(cancel_sender.clone(), resize_sender, server_registry).unwrap();
     });
     // Run control loop for two minutes
-    let registry =
-        TaskRegistrySqlite::new(DATABASE_NAME, "test_table", TablePermanance::DropOnClose);
-    let mut control_loop = ControlLoop::new(&registry, receiver);
     for _ in 0..120 {
         control_loop.run_once();
         std::thread::sleep(std::time::Duration::from_secs(1));
@@ -0,0 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+/// Body of `PUT /admin/workers`, and of its response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WorkerCountModel {
+    pub worker_count: usize,
+}
@@ -1 +1,3 @@
+pub mod admin;
+pub mod schedules;
 pub mod tasks;
@@ -0,0 +1,73 @@
+use crate::core::schedule::{MissedRunPolicy, OverlapPolicy, ScheduleDefinition, ScheduleState};
+use crate::models::tasks::TaskDefinitionModel;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Body of `POST /schedules/{schedule_id}`. `task` is the template of each
+/// run, without `run_at` or `delay_seconds`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScheduleDefinitionModel {
+    pub cron: String,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    pub task: TaskDefinitionModel,
+    #[serde(default)]
+    pub missed_runs: MissedRunPolicy,
+    #[serde(default)]
+    pub overlap: OverlapPolicy,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+impl ScheduleDefinitionModel {
+    pub fn create_schedule_definition(&self) -> ScheduleDefinition {
+        ScheduleDefinition {
+            cron: self.cron.to_string(),
+            timezone: self.timezone.to_string(),
+            task_definition: self.task.create_task_definition(),
+            missed_runs: self.missed_runs,
+            overlap: self.overlap,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScheduleStateModel {
+    pub schedule_id: String,
+    pub cron: String,
+    pub timezone: String,
+    pub task: TaskDefinitionModel,
+    pub missed_runs: MissedRunPolicy,
+    pub overlap: OverlapPolicy,
+    pub created_at: DateTime<Utc>,
+    /// When the next run is due, unless the cron expression never fires again
+    pub next_run_at: Option<DateTime<Utc>>,
+    pub last_run_at: Option<DateTime<Utc>>,
+    /// The task of the latest run that was started
+    pub last_task_id: Option<String>,
+}
+
+impl ScheduleStateModel {
+    pub fn from_schedule_state(schedule_state: &ScheduleState) -> ScheduleStateModel {
+        let schedule_definition = &schedule_state.schedule_definition;
+        ScheduleStateModel {
+            schedule_id: schedule_state.id.to_string(),
+            cron: schedule_definition.cron.to_string(),
+            timezone: schedule_definition.timezone.to_string(),
+            task: TaskDefinitionModel::from_task_definition(&schedule_definition.task_definition),
+            missed_runs: schedule_definition.missed_runs,
+            overlap: schedule_definition.overlap,
+            created_at: schedule_state.created_at,
+            next_run_at: schedule_state.next_run_at(Utc::now()),
+            last_run_at: schedule_state.last_run_at,
+            last_task_id: schedule_state.last_task_id.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListSchedulesResponse {
+    pub schedules: Vec<ScheduleStateModel>,
+}
@@ -1,40 +1,194 @@
-use crate::core::core_types::{TaskDefinition, TaskState, TaskStatus};
+use crate::core::core_types::{
+    AttemptError, OrphanPolicy, TaskDefinition, TaskError, TaskEvent, TaskEventKind, TaskOutput,
+    TaskState, TaskStatus,
+};
+use crate::core::retry_policy::RetryPolicy;
+use chrono::{DateTime, SubsecRound, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TaskDefinitionModel {
-    pub sleep_time_seconds: u16,
-    pub message: String,
-    pub output_path: String,
+    pub kind: String,
+    #[serde(default)]
+    pub parameters: serde_json::Value,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    #[serde(flatten)]
+    pub retry_policy: RetryPolicy,
+    #[serde(default)]
+    pub timeout_seconds: Option<f64>,
+    #[serde(default)]
+    pub on_orphaned: Option<OrphanPolicy>,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub queue: Option<String>,
+    /// When to run the task. At most one of this and `delay_seconds`.
+    #[serde(default)]
+    pub run_at: Option<DateTime<Utc>>,
+    /// How long after being submitted to run the task
+    #[serde(default)]
+    pub delay_seconds: Option<f64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TaskStateModel {
     pub status: TaskStatus,
     pub name: String,
-    pub sleep_time_seconds: u16,
+    pub kind: String,
+    pub parameters: serde_json::Value,
+    pub depends_on: Vec<String>,
+    pub output: Option<TaskOutputModel>,
+    #[serde(flatten)]
+    pub retry_policy: RetryPolicy,
+    pub attempt: u32,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub attempt_errors: Vec<AttemptErrorModel>,
+    /// Why the task failed, for tasks that did
+    pub error: Option<TaskErrorModel>,
+    pub timeout_seconds: Option<f64>,
+    pub on_orphaned: Option<OrphanPolicy>,
+    pub priority: i32,
+    pub queue: Option<String>,
+    pub run_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub queued_at: Option<DateTime<Utc>>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// How long the latest attempt ran, once the task has finished
+    pub duration_seconds: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttemptErrorModel {
+    pub attempt: u32,
+    pub kind: String,
+    pub message: String,
+}
+
+impl AttemptErrorModel {
+    pub fn from_attempt_error(attempt_error: &AttemptError) -> AttemptErrorModel {
+        AttemptErrorModel {
+            attempt: attempt_error.attempt,
+            kind: attempt_error.kind.to_string(),
+            message: attempt_error.message.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaskErrorModel {
+    pub kind: String,
     pub message: String,
-    pub output_path: String,
+    pub exit_code: Option<i32>,
+    pub backtrace: Option<String>,
+}
+
+impl TaskErrorModel {
+    pub fn from_task_error(task_error: &TaskError) -> TaskErrorModel {
+        TaskErrorModel {
+            kind: task_error.kind.to_string(),
+            message: task_error.message.to_string(),
+            exit_code: task_error.exit_code,
+            backtrace: task_error.backtrace.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaskOutputModel {
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl TaskOutputModel {
+    pub fn from_task_output(task_output: &TaskOutput) -> TaskOutputModel {
+        TaskOutputModel {
+            exit_code: task_output.exit_code,
+            stdout: task_output.stdout.to_string(),
+            stderr: task_output.stderr.to_string(),
+        }
+    }
 }
 
 impl TaskStateModel {
     pub fn from_task_state(task_state: &TaskState) -> TaskStateModel {
         TaskStateModel {
-            status: (&task_state.status).clone(),
+            status: task_state.status.clone(),
             name: task_state.name.to_string(),
-            sleep_time_seconds: task_state.sleep_time_seconds,
-            message: task_state.message.to_string(),
-            output_path: task_state.output_path.to_string(),
+            kind: task_state.kind.to_string(),
+            parameters: task_state.parameters.clone(),
+            depends_on: task_state.depends_on.clone(),
+            output: task_state
+                .output
+                .as_ref()
+                .map(TaskOutputModel::from_task_output),
+            retry_policy: task_state.retry_policy.clone(),
+            attempt: task_state.attempt,
+            next_attempt_at: task_state.next_attempt_at,
+            attempt_errors: task_state
+                .attempt_errors
+                .iter()
+                .map(AttemptErrorModel::from_attempt_error)
+                .collect(),
+            error: task_state
+                .error
+                .as_ref()
+                .map(TaskErrorModel::from_task_error),
+            timeout_seconds: task_state.timeout_seconds,
+            on_orphaned: task_state.on_orphaned,
+            priority: task_state.priority,
+            queue: task_state.queue.clone(),
+            run_at: task_state.run_at,
+            created_at: task_state.created_at,
+            queued_at: task_state.queued_at,
+            started_at: task_state.started_at,
+            finished_at: task_state.finished_at,
+            duration_seconds: task_state
+                .duration()
+                .map(|x| x.num_milliseconds() as f64 / 1000.0),
         }
     }
 }
 
 impl TaskDefinitionModel {
+    pub fn from_task_definition(task_definition: &TaskDefinition) -> TaskDefinitionModel {
+        TaskDefinitionModel {
+            kind: task_definition.kind.to_string(),
+            parameters: task_definition.parameters.clone(),
+            depends_on: task_definition.depends_on.clone(),
+            retry_policy: task_definition.retry_policy.clone(),
+            timeout_seconds: task_definition.timeout_seconds,
+            on_orphaned: task_definition.on_orphaned,
+            priority: task_definition.priority,
+            queue: task_definition.queue.clone(),
+            run_at: task_definition.run_at,
+            delay_seconds: None,
+        }
+    }
+
+    /// `run_at`, or `delay_seconds` from now.
+    fn run_at(&self) -> Option<DateTime<Utc>> {
+        self.run_at.or_else(|| {
+            let delay_milliseconds = (self.delay_seconds? * 1000.0) as i64;
+            Some(Utc::now() + chrono::Duration::milliseconds(delay_milliseconds))
+        })
+    }
+
     pub fn create_task_definition(&self) -> TaskDefinition {
         TaskDefinition {
-            sleep_time_seconds: self.sleep_time_seconds,
-            message: self.message.to_string(),
-            output_path: self.output_path.to_string(),
+            kind: self.kind.to_string(),
+            parameters: self.parameters.clone(),
+            depends_on: self.depends_on.clone(),
+            retry_policy: self.retry_policy.clone(),
+            timeout_seconds: self.timeout_seconds,
+            on_orphaned: self.on_orphaned,
+            // Sent as a header rather than in the body
+            idempotency_key: None,
+            priority: self.priority,
+            queue: self.queue.clone(),
+            run_at: self.run_at().map(|x| x.trunc_subsecs(3)),
         }
     }
 }
@@ -50,3 +204,48 @@ pub struct GetTaskStateResponse {
     pub task_id: String,
     pub task_state: TaskStateModel,
 }
+
+/// Query string of `GET /tasks`. `status` is a comma separated list and the
+/// timestamps are RFC 3339.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListTasksQueryModel {
+    pub status: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub finished_after: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    pub sort: Option<String>,
+    pub queue: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListTasksResponse {
+    pub tasks: Vec<TaskStateModel>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaskEventModel {
+    pub at: DateTime<Utc>,
+    pub kind: TaskEventKind,
+    /// Status of the task right after the event
+    pub status: TaskStatus,
+    pub message: Option<String>,
+}
+
+impl TaskEventModel {
+    pub fn from_task_event(task_event: &TaskEvent) -> TaskEventModel {
+        TaskEventModel {
+            at: task_event.at,
+            kind: task_event.kind,
+            status: task_event.status.clone(),
+            message: task_event.message.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaskEventsResponse {
+    pub task_id: String,
+    pub events: Vec<TaskEventModel>,
+}
@@ -1,2 +1,4 @@
 pub mod task_registry;
+pub mod task_registry_in_memory;
 pub mod task_registry_sqlite;
+pub mod task_registry_sqlite_migrations;
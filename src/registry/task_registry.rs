@@ -1,25 +1,235 @@
 use std::collections::HashSet;
 use std::fmt::{self};
 
-use crate::core::core_types::{NewTaskInfo, TaskState, TaskStatus};
+use chrono::{DateTime, Utc};
 
-#[derive(Debug, Clone)]
-pub struct TaskNotFoundError {
-    pub task_id: String,
+use crate::core::core_types::{
+    AttemptError, NewTaskInfo, TaskError, TaskEvent, TaskOutput, TaskState, TaskStatus,
+};
+use crate::core::schedule::{NewScheduleInfo, ScheduleState};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegistryError {
+    NotFound {
+        task_id: String,
+    },
+    /// A task with this id already exists
+    Conflict {
+        task_id: String,
+    },
+    /// The state machine does not allow the change, or the task changed
+    /// status while it was being updated.
+    InvalidTransition {
+        task_id: String,
+        from: TaskStatus,
+        to: TaskStatus,
+    },
+    InvalidCursor {
+        cursor: String,
+    },
+    ScheduleNotFound {
+        schedule_id: String,
+    },
+    /// A schedule with this id already exists
+    ScheduleConflict {
+        schedule_id: String,
+    },
+    /// The storage backend failed
+    Storage {
+        message: String,
+    },
+    /// A stored task could not be read back
+    CorruptRow {
+        message: String,
+    },
+    /// The database was written by a newer version
+    UnsupportedSchema {
+        version: usize,
+        supported: usize,
+    },
 }
 
-impl fmt::Display for TaskNotFoundError {
+impl fmt::Display for RegistryError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "no task for task id {}", self.task_id)
+        match self {
+            RegistryError::NotFound { task_id } => write!(f, "no task for task id {task_id}"),
+            RegistryError::Conflict { task_id } => {
+                write!(f, "a task with task id {task_id} already exists")
+            }
+            RegistryError::InvalidTransition { task_id, from, to } => {
+                write!(f, "task {task_id} cannot move from {from} to {to}")
+            }
+            RegistryError::InvalidCursor { cursor } => write!(f, "invalid cursor {cursor}"),
+            RegistryError::ScheduleNotFound { schedule_id } => {
+                write!(f, "no schedule for schedule id {schedule_id}")
+            }
+            RegistryError::ScheduleConflict { schedule_id } => {
+                write!(
+                    f,
+                    "a schedule with schedule id {schedule_id} already exists"
+                )
+            }
+            RegistryError::Storage { message } => write!(f, "storage error: {message}"),
+            RegistryError::CorruptRow { message } => write!(f, "corrupt task row: {message}"),
+            RegistryError::UnsupportedSchema { version, supported } => write!(
+                f,
+                "schema version {version} is newer than the supported version {supported}"
+            ),
+        }
     }
 }
 
-pub trait TaskRegistry {
-    fn get_task(self: &Self, task_id: &str) -> Result<TaskState, TaskNotFoundError>;
-    fn update_task_from_control_loop(&self, task_id: &str, status: TaskStatus);
-    fn create_task(self: &Self, new_task_info: &NewTaskInfo) -> TaskState;
+impl std::error::Error for RegistryError {}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum TaskSort {
+    /// Oldest first
+    #[default]
+    CreatedAt,
+    Name,
+}
+
+impl std::str::FromStr for TaskSort {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<TaskSort, Self::Err> {
+        match input {
+            "created_at" => Ok(TaskSort::CreatedAt),
+            "name" => Ok(TaskSort::Name),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The cursor after a task in `TaskSort::CreatedAt` order, from its
+/// `created_at` in milliseconds and a number that orders tasks created in the
+/// same millisecond.
+pub fn created_at_cursor(created_at: i64, tiebreak: i64) -> String {
+    format!("{created_at}:{tiebreak}")
+}
+
+pub fn parse_created_at_cursor(cursor: &str) -> Result<(i64, i64), RegistryError> {
+    let invalid_cursor = || RegistryError::InvalidCursor {
+        cursor: cursor.to_string(),
+    };
+    let (created_at, tiebreak) = cursor.split_once(':').ok_or_else(invalid_cursor)?;
+    Ok((
+        created_at.parse().map_err(|_| invalid_cursor())?,
+        tiebreak.parse().map_err(|_| invalid_cursor())?,
+    ))
+}
+
+/// Selects one page of tasks. An empty `statuses` matches every status.
+#[derive(Debug, Clone, Default)]
+pub struct TaskQuery {
+    pub statuses: HashSet<TaskStatus>,
+    pub created_after: Option<DateTime<Utc>>,
+    /// Only matches finished tasks
+    pub finished_after: Option<DateTime<Utc>>,
+    pub queue: Option<String>,
+    pub sort: TaskSort,
+    pub limit: usize,
+    /// Where the previous page ended, taken from its `TaskPage::next_cursor`.
+    /// Only valid with the same sort.
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskPage {
+    pub tasks: Vec<TaskState>,
+    /// Empty on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// Implementations handle their own locking, so that one registry can be
+/// shared by the API and the control loop as an `Arc<dyn TaskRegistry>`.
+pub trait TaskRegistry: Send + Sync {
+    fn get_task(&self, task_id: &str) -> Result<TaskState, RegistryError>;
+    /// Moves a task to `status` if `TaskStatus::can_transition_to` allows it
+    /// from the task's current status.
+    fn update_task_from_control_loop(
+        &self,
+        task_id: &str,
+        status: TaskStatus,
+    ) -> Result<(), RegistryError>;
+    /// Like `update_task_from_control_loop`, also storing why the task failed.
+    fn fail_task(
+        &self,
+        task_id: &str,
+        status: TaskStatus,
+        error: &TaskError,
+    ) -> Result<(), RegistryError>;
+    /// Atomically moves a `PENDING` task to `QUEUED` and starts its next
+    /// attempt. Returns false if the task was not pending, in which case it
+    /// must not be dispatched.
+    fn claim_task(&self, task_id: &str) -> Result<bool, RegistryError>;
+    fn record_attempt_error(
+        &self,
+        task_id: &str,
+        attempt_error: &AttemptError,
+    ) -> Result<(), RegistryError>;
+    /// Puts a failed or orphaned task back to `PENDING`, not to be dispatched
+    /// before `next_attempt_at`.
+    fn schedule_retry(
+        &self,
+        task_id: &str,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<(), RegistryError>;
+    fn set_task_output(&self, task_id: &str, output: &TaskOutput) -> Result<(), RegistryError>;
+    fn create_task(&self, new_task_info: &NewTaskInfo) -> Result<TaskState, RegistryError>;
     fn get_tasks<'a>(
-        self: &'a Self,
+        &'a self,
         statuses: &'a HashSet<TaskStatus>,
-    ) -> Box<dyn Iterator<Item = TaskState> + 'a>;
+    ) -> Result<Box<dyn Iterator<Item = TaskState> + 'a>, RegistryError>;
+    /// `SCHEDULED` tasks whose `run_at` is not after `now`, earliest first.
+    fn get_due_tasks<'a>(
+        &'a self,
+        now: DateTime<Utc>,
+    ) -> Result<Box<dyn Iterator<Item = TaskState> + 'a>, RegistryError>;
+    fn get_task_page(&self, query: &TaskQuery) -> Result<TaskPage, RegistryError>;
+    /// Everything that happened to a task, oldest first.
+    fn get_task_events(&self, task_id: &str) -> Result<Vec<TaskEvent>, RegistryError>;
+
+    fn create_schedule(
+        &self,
+        new_schedule_info: &NewScheduleInfo,
+    ) -> Result<ScheduleState, RegistryError>;
+    fn get_schedule(&self, schedule_id: &str) -> Result<ScheduleState, RegistryError>;
+    /// Oldest first.
+    fn get_schedules(&self) -> Result<Vec<ScheduleState>, RegistryError>;
+    /// Tasks already created by the schedule are left alone.
+    fn delete_schedule(&self, schedule_id: &str) -> Result<(), RegistryError>;
+    /// Records that the run due at `run_at` was handled, by creating the task
+    /// `task_id` or, without one, by skipping the run.
+    fn record_schedule_run(
+        &self,
+        schedule_id: &str,
+        run_at: DateTime<Utc>,
+        task_id: Option<&str>,
+    ) -> Result<(), RegistryError>;
+
+    /// Whether a task with these dependencies would close a cycle through the
+    /// tasks already in the registry.
+    fn creates_dependency_cycle(
+        &self,
+        task_id: &str,
+        depends_on: &[String],
+    ) -> Result<bool, RegistryError> {
+        let mut to_visit = depends_on.to_vec();
+        let mut visited = HashSet::new();
+        while let Some(current) = to_visit.pop() {
+            if current == task_id {
+                return Ok(true);
+            }
+            if !visited.insert(current.to_string()) {
+                continue;
+            }
+            match self.get_task(&current) {
+                Ok(task_state) => to_visit.extend(task_state.depends_on),
+                Err(RegistryError::NotFound { .. }) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(false)
+    }
 }
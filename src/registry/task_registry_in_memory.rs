@@ -0,0 +1,393 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+
+use crate::core::core_types::{
+    timestamp_now, AttemptError, NewTaskInfo, TaskError, TaskEvent, TaskEventKind, TaskOutput,
+    TaskState, TaskStatus,
+};
+use crate::core::schedule::{NewScheduleInfo, ScheduleState};
+use crate::registry::task_registry::{
+    created_at_cursor, parse_created_at_cursor, RegistryError, TaskPage, TaskQuery, TaskRegistry,
+    TaskSort,
+};
+
+#[derive(Default)]
+struct Tasks {
+    /// In creation order
+    task_states: Vec<TaskState>,
+    /// Position of each task in `task_states`
+    positions: HashMap<String, usize>,
+    events: HashMap<String, Vec<TaskEvent>>,
+    /// In creation order
+    schedules: Vec<ScheduleState>,
+}
+
+impl Tasks {
+    fn get(&self, task_id: &str) -> Option<&TaskState> {
+        let position = *self.positions.get(task_id)?;
+        Some(&self.task_states[position])
+    }
+
+    fn get_mut(&mut self, task_id: &str) -> Option<&mut TaskState> {
+        let position = *self.positions.get(task_id)?;
+        Some(&mut self.task_states[position])
+    }
+
+    fn schedule_position(&self, schedule_id: &str) -> Result<usize, RegistryError> {
+        self.schedules
+            .iter()
+            .position(|x| x.id == schedule_id)
+            .ok_or_else(|| RegistryError::ScheduleNotFound {
+                schedule_id: schedule_id.to_string(),
+            })
+    }
+
+    /// Appends to the history of a task, along with the status it now has.
+    fn add_event(&mut self, task_id: &str, kind: TaskEventKind, message: Option<String>) {
+        let Some(task_state) = self.get(task_id) else {
+            return;
+        };
+        let event = TaskEvent {
+            at: timestamp_now(),
+            kind,
+            status: task_state.status.clone(),
+            message,
+        };
+        self.events
+            .entry(task_id.to_string())
+            .or_default()
+            .push(event);
+    }
+}
+
+/// A registry that forgets everything when the process exits. Clones share
+/// the same tasks, so one can be handed to the API and one to the control loop.
+#[derive(Clone, Default)]
+pub struct TaskRegistryInMemory {
+    tasks: Arc<Mutex<Tasks>>,
+}
+
+impl TaskRegistryInMemory {
+    pub fn new() -> TaskRegistryInMemory {
+        TaskRegistryInMemory::default()
+    }
+
+    fn update_task<T>(
+        &self,
+        task_id: &str,
+        update: impl FnOnce(&mut TaskState) -> T,
+    ) -> Result<T, RegistryError> {
+        match self.tasks.lock().unwrap().get_mut(task_id) {
+            Some(task_state) => Ok(update(task_state)),
+            None => Err(RegistryError::NotFound {
+                task_id: task_id.to_string(),
+            }),
+        }
+    }
+
+    fn add_event(&self, task_id: &str, kind: TaskEventKind, message: Option<String>) {
+        self.tasks.lock().unwrap().add_event(task_id, kind, message);
+    }
+
+    /// Moves a task to `status` if the state machine allows it, then runs
+    /// `update` on it.
+    fn transition(
+        &self,
+        task_id: &str,
+        status: TaskStatus,
+        update: impl FnOnce(&mut TaskState),
+    ) -> Result<(), RegistryError> {
+        self.update_task(task_id, |task_state| {
+            if !task_state.status.can_transition_to(&status) {
+                return Err(RegistryError::InvalidTransition {
+                    task_id: task_id.to_string(),
+                    from: task_state.status.clone(),
+                    to: status,
+                });
+            }
+            if status == TaskStatus::RUNNING {
+                task_state.started_at = Some(timestamp_now());
+            } else if status.is_finished() {
+                task_state.finished_at = Some(timestamp_now());
+            }
+            task_state.status = status;
+            update(task_state);
+            Ok(())
+        })?
+    }
+}
+
+impl TaskRegistry for TaskRegistryInMemory {
+    fn get_task(&self, task_id: &str) -> Result<TaskState, RegistryError> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .get(task_id)
+            .cloned()
+            .ok_or_else(|| RegistryError::NotFound {
+                task_id: task_id.to_string(),
+            })
+    }
+
+    fn update_task_from_control_loop(
+        &self,
+        task_id: &str,
+        status: TaskStatus,
+    ) -> Result<(), RegistryError> {
+        self.transition(task_id, status, |_| {})?;
+        self.add_event(task_id, TaskEventKind::StatusChanged, None);
+        Ok(())
+    }
+
+    fn fail_task(
+        &self,
+        task_id: &str,
+        status: TaskStatus,
+        error: &TaskError,
+    ) -> Result<(), RegistryError> {
+        self.transition(task_id, status, |task_state| {
+            task_state.error = Some(error.clone());
+        })?;
+        self.add_event(
+            task_id,
+            TaskEventKind::StatusChanged,
+            Some(error.to_string()),
+        );
+        Ok(())
+    }
+
+    fn claim_task(&self, task_id: &str) -> Result<bool, RegistryError> {
+        let claimed = self.update_task(task_id, |task_state| {
+            if task_state.status != TaskStatus::PENDING {
+                return false;
+            }
+            task_state.status = TaskStatus::QUEUED;
+            task_state.attempt += 1;
+            task_state.next_attempt_at = None;
+            task_state.queued_at = Some(timestamp_now());
+            true
+        })?;
+        if claimed {
+            self.add_event(task_id, TaskEventKind::Claimed, None);
+        }
+        Ok(claimed)
+    }
+
+    fn record_attempt_error(
+        &self,
+        task_id: &str,
+        attempt_error: &AttemptError,
+    ) -> Result<(), RegistryError> {
+        self.update_task(task_id, |task_state| {
+            task_state.attempt_errors.push(attempt_error.clone());
+            task_state.attempt_errors.sort_by_key(|x| x.attempt);
+        })?;
+        self.add_event(
+            task_id,
+            TaskEventKind::AttemptFailed,
+            Some(format!(
+                "attempt {} failed with {}: {}",
+                attempt_error.attempt, attempt_error.kind, attempt_error.message
+            )),
+        );
+        Ok(())
+    }
+
+    fn schedule_retry(
+        &self,
+        task_id: &str,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<(), RegistryError> {
+        self.transition(task_id, TaskStatus::PENDING, |task_state| {
+            task_state.next_attempt_at = Some(next_attempt_at);
+        })?;
+        self.add_event(
+            task_id,
+            TaskEventKind::RetryScheduled,
+            Some(format!("next attempt at {next_attempt_at}")),
+        );
+        Ok(())
+    }
+
+    fn set_task_output(&self, task_id: &str, output: &TaskOutput) -> Result<(), RegistryError> {
+        self.update_task(task_id, |task_state| {
+            task_state.output = Some(output.clone())
+        })
+    }
+
+    fn create_task(&self, new_task_info: &NewTaskInfo) -> Result<TaskState, RegistryError> {
+        let mut task_state = TaskState::new(new_task_info);
+        // Listing a dependency twice does not make it count twice
+        let mut seen = HashSet::new();
+        task_state.depends_on.retain(|x| seen.insert(x.to_string()));
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.positions.contains_key(&task_state.name) {
+            return Err(RegistryError::Conflict {
+                task_id: task_state.name,
+            });
+        }
+        let position = tasks.task_states.len();
+        tasks
+            .positions
+            .insert(task_state.name.to_string(), position);
+        tasks.task_states.push(task_state.clone());
+        tasks.add_event(&task_state.name, TaskEventKind::Created, None);
+        Ok(task_state)
+    }
+
+    fn get_tasks<'a>(
+        &'a self,
+        statuses: &'a HashSet<TaskStatus>,
+    ) -> Result<Box<dyn Iterator<Item = TaskState> + 'a>, RegistryError> {
+        let task_states: Vec<TaskState> = self
+            .tasks
+            .lock()
+            .unwrap()
+            .task_states
+            .iter()
+            .filter(|task_state| statuses.contains(&task_state.status))
+            .cloned()
+            .collect();
+        Ok(Box::new(task_states.into_iter()))
+    }
+
+    fn get_due_tasks<'a>(
+        &'a self,
+        now: DateTime<Utc>,
+    ) -> Result<Box<dyn Iterator<Item = TaskState> + 'a>, RegistryError> {
+        let mut task_states: Vec<TaskState> = self
+            .tasks
+            .lock()
+            .unwrap()
+            .task_states
+            .iter()
+            .filter(|task_state| {
+                task_state.status == TaskStatus::SCHEDULED
+                    && task_state.run_at.is_some_and(|x| x <= now)
+            })
+            .cloned()
+            .collect();
+        task_states.sort_by_key(|task_state| task_state.run_at);
+        Ok(Box::new(task_states.into_iter()))
+    }
+
+    fn get_task_page(&self, query: &TaskQuery) -> Result<TaskPage, RegistryError> {
+        let tasks = self.tasks.lock().unwrap();
+        // Same keyset pagination as the SQLite registry, with the position in
+        // creation order standing in for the rowid
+        let mut rows: Vec<(usize, &TaskState)> = tasks
+            .task_states
+            .iter()
+            .enumerate()
+            .filter(|(_, task_state)| {
+                (query.statuses.is_empty() || query.statuses.contains(&task_state.status))
+                    && query
+                        .created_after
+                        .is_none_or(|x| task_state.created_at > x)
+                    && query
+                        .finished_after
+                        .is_none_or(|x| task_state.finished_at.is_some_and(|y| y > x))
+                    && query
+                        .queue
+                        .as_ref()
+                        .is_none_or(|x| task_state.queue.as_ref() == Some(x))
+            })
+            .collect();
+        let created_at_key = |(position, task_state): &(usize, &TaskState)| -> (i64, i64) {
+            (task_state.created_at.timestamp_millis(), *position as i64)
+        };
+        match query.sort {
+            TaskSort::CreatedAt => rows.sort_by_key(created_at_key),
+            TaskSort::Name => rows.sort_by(|a, b| a.1.name.cmp(&b.1.name)),
+        }
+        if let Some(cursor) = &query.cursor {
+            match query.sort {
+                TaskSort::CreatedAt => {
+                    let cursor = parse_created_at_cursor(cursor)?;
+                    rows.retain(|row| created_at_key(row) > cursor);
+                }
+                TaskSort::Name => rows.retain(|(_, task_state)| task_state.name > *cursor),
+            }
+        }
+        let next_cursor = if rows.len() > query.limit {
+            rows.truncate(query.limit);
+            rows.last().map(|row| match query.sort {
+                TaskSort::CreatedAt => {
+                    let (created_at, position) = created_at_key(row);
+                    created_at_cursor(created_at, position)
+                }
+                TaskSort::Name => row.1.name.to_string(),
+            })
+        } else {
+            None
+        };
+        Ok(TaskPage {
+            tasks: rows
+                .into_iter()
+                .map(|(_, task_state)| task_state.clone())
+                .collect(),
+            next_cursor,
+        })
+    }
+
+    fn get_task_events(&self, task_id: &str) -> Result<Vec<TaskEvent>, RegistryError> {
+        let tasks = self.tasks.lock().unwrap();
+        if tasks.get(task_id).is_none() {
+            return Err(RegistryError::NotFound {
+                task_id: task_id.to_string(),
+            });
+        }
+        Ok(tasks.events.get(task_id).cloned().unwrap_or_default())
+    }
+
+    fn create_schedule(
+        &self,
+        new_schedule_info: &NewScheduleInfo,
+    ) -> Result<ScheduleState, RegistryError> {
+        let schedule_state = ScheduleState::new(new_schedule_info);
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.schedule_position(&schedule_state.id).is_ok() {
+            return Err(RegistryError::ScheduleConflict {
+                schedule_id: schedule_state.id,
+            });
+        }
+        tasks.schedules.push(schedule_state.clone());
+        Ok(schedule_state)
+    }
+
+    fn get_schedule(&self, schedule_id: &str) -> Result<ScheduleState, RegistryError> {
+        let tasks = self.tasks.lock().unwrap();
+        let position = tasks.schedule_position(schedule_id)?;
+        Ok(tasks.schedules[position].clone())
+    }
+
+    fn get_schedules(&self) -> Result<Vec<ScheduleState>, RegistryError> {
+        Ok(self.tasks.lock().unwrap().schedules.clone())
+    }
+
+    fn delete_schedule(&self, schedule_id: &str) -> Result<(), RegistryError> {
+        let mut tasks = self.tasks.lock().unwrap();
+        let position = tasks.schedule_position(schedule_id)?;
+        tasks.schedules.remove(position);
+        Ok(())
+    }
+
+    fn record_schedule_run(
+        &self,
+        schedule_id: &str,
+        run_at: DateTime<Utc>,
+        task_id: Option<&str>,
+    ) -> Result<(), RegistryError> {
+        let mut tasks = self.tasks.lock().unwrap();
+        let position = tasks.schedule_position(schedule_id)?;
+        let schedule_state = &mut tasks.schedules[position];
+        schedule_state.last_run_at = Some(run_at);
+        // A skipped run leaves the task of the previous run as the latest
+        if let Some(task_id) = task_id {
+            schedule_state.last_task_id = Some(task_id.to_string());
+        }
+        Ok(())
+    }
+}
@@ -1,47 +1,271 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
 
-use crate::core::core_types::{NewTaskInfo, TaskState, TaskStatus};
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::core::core_types::{
+    timestamp_now, AttemptError, NewTaskInfo, OrphanPolicy, TaskError, TaskEvent, TaskEventKind,
+    TaskOutput, TaskState, TaskStatus,
+};
+use crate::core::schedule::{
+    MissedRunPolicy, NewScheduleInfo, OverlapPolicy, ScheduleDefinition, ScheduleState,
+};
 use crate::registry::task_registry;
+use crate::registry::task_registry::{
+    created_at_cursor, parse_created_at_cursor, RegistryError, TaskPage, TaskQuery, TaskSort,
+};
+use crate::registry::task_registry_sqlite_migrations;
+
+const COLUMNS: [&str; 21] = [
+    "status",
+    "name",
+    "kind",
+    "parameters",
+    "exit_code",
+    "stdout",
+    "stderr",
+    "retry_policy",
+    "attempt",
+    "next_attempt_at",
+    "timeout_seconds",
+    "on_orphaned",
+    "idempotency_key",
+    "created_at",
+    "queued_at",
+    "started_at",
+    "finished_at",
+    "error",
+    "priority",
+    "queue",
+    "run_at",
+];
+
+const SCHEDULE_COLUMNS: [&str; 9] = [
+    "id",
+    "cron",
+    "timezone",
+    "task_definition",
+    "missed_runs",
+    "overlap",
+    "created_at",
+    "last_run_at",
+    "last_task_id",
+];
+
+/// Primary result code of a violated constraint
+const SQLITE_CONSTRAINT: isize = 19;
+/// How long to wait for another connection's lock before failing with
+/// `database is locked`
+const BUSY_TIMEOUT_MILLISECONDS: usize = 5000;
+const MAX_IDLE_READERS: usize = 4;
+/// Most task names bound in one `IN (...)`, well below SQLite's limit on
+/// variables in a statement
+const MAX_NAMES_PER_QUERY: usize = 500;
+
+impl From<sqlite::Error> for RegistryError {
+    fn from(error: sqlite::Error) -> RegistryError {
+        RegistryError::Storage {
+            message: error.to_string(),
+        }
+    }
+}
+
+fn corrupt_row(message: String) -> RegistryError {
+    RegistryError::CorruptRow { message }
+}
+
+/// Column values of a task row, in the order of `COLUMNS`.
+fn serialise_task_state(task_state: &TaskState) -> Vec<sqlite::Value> {
+    let output = task_state.output.as_ref();
+    vec![
+        task_state.status.to_string().into(),
+        task_state.name.to_string().into(),
+        task_state.kind.to_string().into(),
+        task_state.parameters.to_string().into(),
+        optional_value(output.and_then(|output| output.exit_code.map(|x| x as i64))),
+        optional_value(output.map(|output| output.stdout.to_string())),
+        optional_value(output.map(|output| output.stderr.to_string())),
+        serde_json::to_string(&task_state.retry_policy)
+            .unwrap()
+            .into(),
+        (task_state.attempt as i64).into(),
+        optional_value(task_state.next_attempt_at.map(serialise_timestamp)),
+        optional_value(task_state.timeout_seconds),
+        optional_value(task_state.on_orphaned.map(|x| x.to_string())),
+        optional_value(task_state.idempotency_key.clone()),
+        serialise_timestamp(task_state.created_at).into(),
+        optional_value(task_state.queued_at.map(serialise_timestamp)),
+        optional_value(task_state.started_at.map(serialise_timestamp)),
+        optional_value(task_state.finished_at.map(serialise_timestamp)),
+        optional_value(task_state.error.as_ref().map(serialise_task_error)),
+        (task_state.priority as i64).into(),
+        optional_value(task_state.queue.clone()),
+        optional_value(task_state.run_at.map(serialise_timestamp)),
+    ]
+}
+
+/// Inverse of `serialise_task_state`. Dependencies and attempt errors live in
+/// their own tables and are left empty.
+fn deserialise_task_state(values: &[sqlite::Value]) -> Result<TaskState, RegistryError> {
+    let output = match (
+        extract_optional_string(&values[5])?,
+        extract_optional_string(&values[6])?,
+    ) {
+        (Some(stdout), Some(stderr)) => Some(TaskOutput {
+            exit_code: extract_optional_i64(&values[4])?.map(|x| x as i32),
+            stdout,
+            stderr,
+        }),
+        _ => None,
+    };
+    let status = extract_string(&values[0])?;
+    let on_orphaned = match extract_optional_string(&values[11])? {
+        Some(x) => Some(
+            OrphanPolicy::from_str(&x)
+                .map_err(|_| corrupt_row(format!("unknown orphan policy {x}")))?,
+        ),
+        None => None,
+    };
+    Ok(TaskState {
+        status: TaskStatus::from_str(&status)
+            .map_err(|_| corrupt_row(format!("unknown status {status}")))?,
+        name: extract_string(&values[1])?,
+        kind: extract_string(&values[2])?,
+        parameters: serde_json::from_str(&extract_string(&values[3])?)
+            .map_err(|error| corrupt_row(format!("invalid parameters: {error}")))?,
+        depends_on: Vec::new(),
+        output,
+        retry_policy: serde_json::from_str(&extract_string(&values[7])?)
+            .map_err(|error| corrupt_row(format!("invalid retry policy: {error}")))?,
+        attempt: extract_i64(&values[8])? as u32,
+        next_attempt_at: extract_optional_i64(&values[9])?
+            .map(deserialise_timestamp)
+            .transpose()?,
+        attempt_errors: Vec::new(),
+        timeout_seconds: extract_optional_f64(&values[10])?,
+        on_orphaned,
+        idempotency_key: extract_optional_string(&values[12])?,
+        priority: extract_i64(&values[18])? as i32,
+        queue: extract_optional_string(&values[19])?,
+        run_at: extract_optional_i64(&values[20])?
+            .map(deserialise_timestamp)
+            .transpose()?,
+        created_at: deserialise_timestamp(extract_i64(&values[13])?)?,
+        queued_at: extract_optional_i64(&values[14])?
+            .map(deserialise_timestamp)
+            .transpose()?,
+        started_at: extract_optional_i64(&values[15])?
+            .map(deserialise_timestamp)
+            .transpose()?,
+        finished_at: extract_optional_i64(&values[16])?
+            .map(deserialise_timestamp)
+            .transpose()?,
+        error: extract_optional_string(&values[17])?
+            .map(|x| {
+                serde_json::from_str(&x)
+                    .map_err(|error| corrupt_row(format!("invalid task error: {error}")))
+            })
+            .transpose()?,
+    })
+}
+
+/// Column values of a schedule row, in the order of `SCHEDULE_COLUMNS`.
+fn serialise_schedule_state(schedule_state: &ScheduleState) -> Vec<sqlite::Value> {
+    let schedule_definition = &schedule_state.schedule_definition;
+    vec![
+        schedule_state.id.to_string().into(),
+        schedule_definition.cron.to_string().into(),
+        schedule_definition.timezone.to_string().into(),
+        serde_json::to_string(&schedule_definition.task_definition)
+            .unwrap()
+            .into(),
+        schedule_definition.missed_runs.to_string().into(),
+        schedule_definition.overlap.to_string().into(),
+        serialise_timestamp(schedule_state.created_at).into(),
+        optional_value(schedule_state.last_run_at.map(serialise_timestamp)),
+        optional_value(schedule_state.last_task_id.clone()),
+    ]
+}
+
+fn deserialise_schedule_state(values: &[sqlite::Value]) -> Result<ScheduleState, RegistryError> {
+    let missed_runs = extract_string(&values[4])?;
+    let overlap = extract_string(&values[5])?;
+    Ok(ScheduleState {
+        id: extract_string(&values[0])?,
+        schedule_definition: ScheduleDefinition {
+            cron: extract_string(&values[1])?,
+            timezone: extract_string(&values[2])?,
+            task_definition: serde_json::from_str(&extract_string(&values[3])?)
+                .map_err(|error| corrupt_row(format!("invalid task definition: {error}")))?,
+            missed_runs: MissedRunPolicy::from_str(&missed_runs)
+                .map_err(|_| corrupt_row(format!("unknown missed run policy {missed_runs}")))?,
+            overlap: OverlapPolicy::from_str(&overlap)
+                .map_err(|_| corrupt_row(format!("unknown overlap policy {overlap}")))?,
+        },
+        created_at: deserialise_timestamp(extract_i64(&values[6])?)?,
+        last_run_at: extract_optional_i64(&values[7])?
+            .map(deserialise_timestamp)
+            .transpose()?,
+        last_task_id: extract_optional_string(&values[8])?,
+    })
+}
+
+fn serialise_task_error(error: &TaskError) -> String {
+    serde_json::to_string(error).unwrap()
+}
 
-use sqlite;
+fn serialise_timestamp(timestamp: DateTime<Utc>) -> i64 {
+    timestamp.timestamp_millis()
+}
 
-type SerialisedTaskState = (String, String, i64, String, String);
+fn deserialise_timestamp(millis: i64) -> Result<DateTime<Utc>, RegistryError> {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .ok_or_else(|| corrupt_row(format!("invalid timestamp {millis}")))
+}
 
-fn serialise_task_state(task_state: &TaskState) -> SerialisedTaskState {
-    (
-        task_state.status.to_string(),
-        task_state.name.to_string(),
-        task_state.sleep_time_seconds as i64,
-        task_state.message.to_string(),
-        task_state.output_path.to_string(),
-    )
+fn extract_string(value: &sqlite::Value) -> Result<String, RegistryError> {
+    match &value {
+        sqlite::Value::String(i) => Ok(i.to_string()),
+        _ => Err(corrupt_row(format!("expected text, found {value:?}"))),
+    }
 }
 
-fn deserialise_task_state(serialised_task_state: SerialisedTaskState) -> TaskState {
-    TaskState {
-        status: TaskStatus::from_str(&serialised_task_state.0).unwrap(),
-        name: serialised_task_state.1,
-        sleep_time_seconds: serialised_task_state.2 as u16,
-        message: serialised_task_state.3,
-        output_path: serialised_task_state.4,
+fn extract_i64(value: &sqlite::Value) -> Result<i64, RegistryError> {
+    match &value {
+        sqlite::Value::Integer(i) => Ok(*i),
+        _ => Err(corrupt_row(format!("expected an integer, found {value:?}"))),
     }
 }
 
-fn extract_string(value: &sqlite::Value) -> String {
+fn extract_optional_string(value: &sqlite::Value) -> Result<Option<String>, RegistryError> {
     match &value {
-        sqlite::Value::String(i) => i.to_string(),
-        _ => panic!(),
+        sqlite::Value::Null => Ok(None),
+        _ => extract_string(value).map(Some),
     }
 }
 
-fn extract_i64(value: &sqlite::Value) -> i64 {
+fn extract_optional_i64(value: &sqlite::Value) -> Result<Option<i64>, RegistryError> {
     match &value {
-        sqlite::Value::Integer(i) => *i,
-        _ => panic!(),
+        sqlite::Value::Null => Ok(None),
+        _ => extract_i64(value).map(Some),
     }
 }
 
+fn extract_optional_f64(value: &sqlite::Value) -> Result<Option<f64>, RegistryError> {
+    match &value {
+        sqlite::Value::Float(x) => Ok(Some(*x)),
+        sqlite::Value::Integer(i) => Ok(Some(*i as f64)),
+        sqlite::Value::Null => Ok(None),
+        _ => Err(corrupt_row(format!("expected a number, found {value:?}"))),
+    }
+}
+
+fn optional_value<T: Into<sqlite::Value>>(value: Option<T>) -> sqlite::Value {
+    value.map_or(sqlite::Value::Null, Into::into)
+}
+
 #[derive(PartialEq, Default)]
 pub enum TablePermanance {
     #[default]
@@ -50,122 +274,787 @@ pub enum TablePermanance {
     DropOnClose,
 }
 
+/// Shared between threads, see `read` and `write`.
 pub struct TaskRegistrySqlite {
     table_name: String,
-    connection: sqlite::Connection,
+    database: String,
+    /// SQLite allows one writer at a time, so writes take turns on this
+    /// connection rather than wait on each other's locks.
+    writer: Mutex<sqlite::Connection>,
+    /// Idle connections for reads, which with WAL journaling do not wait for
+    /// the writer. Unused for in-memory databases, whose tables only the
+    /// connection that created them can see.
+    readers: Mutex<Vec<sqlite::Connection>>,
     table_permanence: TablePermanance,
 }
 
+/// The registry's tables, seen through one connection.
+struct Tables<'a> {
+    table_name: &'a str,
+    connection: &'a sqlite::Connection,
+}
+
+fn is_in_memory(database: &str) -> bool {
+    database.is_empty() || database == ":memory:"
+}
+
+fn open_connection(database: &str) -> Result<sqlite::Connection, RegistryError> {
+    let mut connection = sqlite::Connection::open(database)?;
+    // Other processes, such as a second runner or `--migrate`, can still hold
+    // the lock for a while
+    connection.set_busy_timeout(BUSY_TIMEOUT_MILLISECONDS)?;
+    if !is_in_memory(database) {
+        connection.execute("PRAGMA journal_mode = WAL")?;
+    }
+    Ok(connection)
+}
+
 impl TaskRegistrySqlite {
     pub fn new(
         database: &str,
         table_name: &str,
         table_permanence: TablePermanance,
-    ) -> TaskRegistrySqlite {
+    ) -> Result<TaskRegistrySqlite, RegistryError> {
         let table_name = table_name.to_string();
-        let query = format!("CREATE TABLE IF NOT EXISTS {table_name} (status TEXT, name TEXT PRIMARY KEY, sleep_time_seconds INTEGER, message TEXT, output_path TEXT);");
-        let connection = sqlite::Connection::open(database).unwrap();
-        connection.execute(query).unwrap();
-        TaskRegistrySqlite {
-            table_name: table_name.to_string(),
-            connection: connection,
-            table_permanence: table_permanence,
+        let connection = open_connection(database)?;
+        task_registry_sqlite_migrations::migrate(&connection, &table_name)?;
+        Ok(TaskRegistrySqlite {
+            table_name,
+            database: database.to_string(),
+            writer: Mutex::new(connection),
+            readers: Mutex::new(Vec::new()),
+            table_permanence,
+        })
+    }
+
+    /// Brings the tables named after `table_name` up to the current schema
+    /// version, which `new` also does, and returns that version.
+    pub fn migrate(database: &str, table_name: &str) -> Result<usize, RegistryError> {
+        let connection = open_connection(database)?;
+        task_registry_sqlite_migrations::migrate(&connection, table_name)
+    }
+
+    /// Runs `operation` on a connection of its own where there is one, so
+    /// reads from different threads do not wait for each other.
+    fn read<T>(
+        &self,
+        operation: impl FnOnce(&Tables) -> Result<T, RegistryError>,
+    ) -> Result<T, RegistryError> {
+        if is_in_memory(&self.database) {
+            let connection = self.writer.lock().unwrap();
+            return operation(&self.tables(&connection));
+        }
+        let idle_connection = self.readers.lock().unwrap().pop();
+        let connection = match idle_connection {
+            Some(connection) => connection,
+            None => open_connection(&self.database)?,
+        };
+        let result = operation(&self.tables(&connection));
+        let mut readers = self.readers.lock().unwrap();
+        if readers.len() < MAX_IDLE_READERS {
+            readers.push(connection);
+        }
+        result
+    }
+
+    /// Runs `operation` in a transaction, which is rolled back if it fails.
+    /// Operations that read before they write see no changes from other
+    /// writers in between.
+    fn write<T>(
+        &self,
+        operation: impl FnOnce(&Tables) -> Result<T, RegistryError>,
+    ) -> Result<T, RegistryError> {
+        let connection = self.writer.lock().unwrap();
+        connection.execute("BEGIN IMMEDIATE")?;
+        let result = operation(&self.tables(&connection))
+            .and_then(|x| connection.execute("COMMIT").map(|_| x).map_err(Into::into));
+        if result.is_err() {
+            if let Err(error) = connection.execute("ROLLBACK") {
+                println!("Could not roll back: {error}");
+            }
+        }
+        result
+    }
+
+    fn tables<'a>(&'a self, connection: &'a sqlite::Connection) -> Tables<'a> {
+        Tables {
+            table_name: &self.table_name,
+            connection,
         }
     }
 }
 
-impl task_registry::TaskRegistry for TaskRegistrySqlite {
-    fn get_task(self: &Self, task_id: &str) -> Result<TaskState, task_registry::TaskNotFoundError> {
+impl Tables<'_> {
+    /// Runs a statement that returns no rows and returns the number of rows
+    /// it changed.
+    fn execute(
+        &self,
+        query: &str,
+        bindings: Vec<(&str, sqlite::Value)>,
+    ) -> Result<usize, RegistryError> {
+        let mut statement = self.connection.prepare(query)?;
+        statement.bind_iter(bindings)?;
+        statement.next()?;
+        Ok(self.connection.change_count())
+    }
+
+    /// Like `execute` for a statement that should change exactly the row of
+    /// one task.
+    fn execute_for_task(
+        &self,
+        task_id: &str,
+        query: &str,
+        bindings: Vec<(&str, sqlite::Value)>,
+    ) -> Result<(), RegistryError> {
+        match self.execute(query, bindings)? {
+            0 => Err(RegistryError::NotFound {
+                task_id: task_id.to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn get_status(&self, task_id: &str) -> Result<TaskStatus, RegistryError> {
         let table_name = &self.table_name;
-        let query = format!("SELECT * FROM {table_name} WHERE name = ?");
-        let mut statement = self.connection.prepare(query).unwrap();
-        statement.bind((1, task_id)).unwrap();
-        let mut cursor = statement.iter();
-        let optional_values = cursor.try_next().unwrap();
-        if let Some(values) = optional_values {
-            let serialised_task_state: SerialisedTaskState = (
-                extract_string(&values[0]),
-                extract_string(&values[1]),
-                extract_i64(&values[2]),
-                extract_string(&values[3]),
-                extract_string(&values[4]),
-            );
-            Ok(deserialise_task_state(serialised_task_state))
+        let query = format!("SELECT status FROM {table_name} WHERE name = ?");
+        let mut statement = self.connection.prepare(query)?;
+        statement.bind((1, task_id))?;
+        match statement.iter().next() {
+            Some(row_result) => {
+                let status = extract_string(&row_result?[0])?;
+                TaskStatus::from_str(&status)
+                    .map_err(|_| corrupt_row(format!("unknown status {status}")))
+            }
+            None => Err(RegistryError::NotFound {
+                task_id: task_id.to_string(),
+            }),
+        }
+    }
+
+    /// Moves a task to `status`, also setting `set_columns`, if the state
+    /// machine allows it. The update is conditional on the status it was
+    /// checked against, so a concurrent change makes it fail rather than be
+    /// overwritten. Starting and finishing are timestamped.
+    fn transition(
+        &self,
+        task_id: &str,
+        status: TaskStatus,
+        set_columns: &str,
+        mut bindings: Vec<(&str, sqlite::Value)>,
+    ) -> Result<(), RegistryError> {
+        let expected = self.get_status(task_id)?;
+        if !expected.can_transition_to(&status) {
+            return Err(RegistryError::InvalidTransition {
+                task_id: task_id.to_string(),
+                from: expected,
+                to: status,
+            });
+        }
+        let timestamp_column = if status == TaskStatus::RUNNING {
+            Some("started_at")
+        } else if status.is_finished() {
+            Some("finished_at")
         } else {
-            Err(task_registry::TaskNotFoundError {
+            None
+        };
+        let mut set_columns = set_columns.to_string();
+        if let Some(column) = timestamp_column {
+            set_columns.push_str(&format!(", {column} = :now"));
+            bindings.push((":now", serialise_timestamp(timestamp_now()).into()));
+        }
+        let table_name = &self.table_name;
+        let query = format!(
+            "UPDATE {table_name} SET status = :status{set_columns} WHERE name = :name AND status = :expected"
+        );
+        bindings.extend([
+            (":status", status.to_string().into()),
+            (":name", task_id.into()),
+            (":expected", expected.to_string().into()),
+        ]);
+        if self.execute(&query, bindings)? == 0 {
+            return Err(RegistryError::InvalidTransition {
                 task_id: task_id.to_string(),
-            })
+                from: self.get_status(task_id)?,
+                to: status,
+            });
         }
+        Ok(())
     }
 
-    fn update_task_from_control_loop(&self, task_id: &str, status: TaskStatus) {
+    /// Appends to the history of a task, along with the status it now has.
+    fn add_event(
+        &self,
+        task_id: &str,
+        kind: TaskEventKind,
+        message: Option<String>,
+    ) -> Result<(), RegistryError> {
         let table_name = &self.table_name;
-        let query = format!("UPDATE {table_name} SET status = :status WHERE name = :name");
-        let mut statement = self.connection.prepare(query).unwrap();
-        statement
-            .bind_iter::<_, (_, sqlite::Value)>([
-                (":status", status.to_string().into()),
+        let query = format!(
+            "INSERT INTO {table_name}_events (name, at, kind, status, message) SELECT name, :at, :kind, status, :message FROM {table_name} WHERE name = :name"
+        );
+        self.execute_for_task(
+            task_id,
+            &query,
+            vec![
+                (":at", serialise_timestamp(timestamp_now()).into()),
+                (":kind", kind.to_string().into()),
+                (":message", optional_value(message)),
                 (":name", task_id.into()),
-            ])
-            .unwrap();
-        let state = statement.next().unwrap();
-        assert_eq!(state, sqlite::State::Done);
+            ],
+        )
+    }
+
+    /// Reads the tasks returned by `statement`, each with any values selected
+    /// after `COLUMNS`.
+    fn read_task_rows(
+        &self,
+        statement: &mut sqlite::Statement,
+    ) -> Result<Vec<(TaskState, Vec<sqlite::Value>)>, RegistryError> {
+        let mut task_states = Vec::new();
+        let mut extra_columns = Vec::new();
+        for row_result in statement.iter() {
+            let mut values = Vec::<sqlite::Value>::from(row_result?);
+            extra_columns.push(values.split_off(COLUMNS.len()));
+            task_states.push(deserialise_task_state(&values)?);
+        }
+        for chunk in task_states.chunks_mut(MAX_NAMES_PER_QUERY) {
+            self.add_related_rows(chunk)?;
+        }
+        Ok(task_states.into_iter().zip(extra_columns).collect())
     }
 
-    fn create_task(&self, new_task_info: &NewTaskInfo) -> TaskState {
-        let task_state = TaskState::new(new_task_info);
-        let serialised_state = serialise_task_state(&task_state);
+    /// Fills in the parts of the task states that are kept in other tables,
+    /// with one query per table rather than per task.
+    fn add_related_rows(&self, task_states: &mut [TaskState]) -> Result<(), RegistryError> {
+        let positions: HashMap<String, usize> = task_states
+            .iter()
+            .enumerate()
+            .map(|(i, task_state)| (task_state.name.to_string(), i))
+            .collect();
+        let names: Vec<String> = task_states.iter().map(|x| x.name.to_string()).collect();
         let table_name = &self.table_name;
+        let placeholders = vec!["?"; names.len()].join(", ");
+
         let query = format!(
-            "INSERT INTO {table_name}  VALUES (:status, :name, :sleep_time_seconds, :message, :output_path) "
+            "SELECT name, depends_on FROM {table_name}_dependencies WHERE name IN ({placeholders}) ORDER BY rowid"
         );
-        let mut statement = self.connection.prepare(query).unwrap();
+        for values in self.select_for_names(&query, &names)? {
+            let position = positions[&extract_string(&values[0])?];
+            task_states[position]
+                .depends_on
+                .push(extract_string(&values[1])?);
+        }
+
+        let query = format!(
+            "SELECT name, attempt, kind, message FROM {table_name}_attempt_errors WHERE name IN ({placeholders}) ORDER BY attempt, rowid"
+        );
+        for values in self.select_for_names(&query, &names)? {
+            let position = positions[&extract_string(&values[0])?];
+            task_states[position].attempt_errors.push(AttemptError {
+                attempt: extract_i64(&values[1])? as u32,
+                kind: extract_string(&values[2])?,
+                message: extract_string(&values[3])?,
+            });
+        }
+        Ok(())
+    }
+
+    /// Runs `query` with `names` bound to its positional parameters.
+    fn select_for_names(
+        &self,
+        query: &str,
+        names: &[String],
+    ) -> Result<Vec<Vec<sqlite::Value>>, RegistryError> {
+        let mut statement = self.connection.prepare(query)?;
+        statement.bind_iter::<_, (usize, sqlite::Value)>(
+            names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| (i + 1, name.as_str().into())),
+        )?;
         statement
-            .bind_iter::<_, (_, sqlite::Value)>([
-                (":status", serialised_state.0.into()),
-                (":name", serialised_state.1.into()),
-                (":sleep_time_seconds", serialised_state.2.into()),
-                (":message", serialised_state.3.into()),
-                (":output_path", serialised_state.4.into()),
-            ])
-            .unwrap();
-        let state = statement.next().unwrap();
-        assert_eq!(state, sqlite::State::Done);
-        task_state
+            .iter()
+            .map(|row_result| Ok(Vec::from(row_result?)))
+            .collect()
     }
 
-    fn get_tasks<'a>(
-        self: &'a Self,
-        statuses: &'a HashSet<TaskStatus>,
-    ) -> Box<dyn Iterator<Item = TaskState> + 'a> {
-        let statuses_vec = Vec::from_iter(statuses.into_iter().map(|x| x.to_string()));
-        let question_marks =
-            Vec::from_iter(statuses.into_iter().map(|_x| "?".to_string())).join(", ");
+    fn add_dependencies(&self, task_id: &str, depends_on: &[String]) -> Result<(), RegistryError> {
         let table_name = &self.table_name;
-        let query = format!("SELECT * FROM {table_name} WHERE status in ({question_marks})");
-        let mut statement = self.connection.prepare(query).unwrap();
+        let query = format!(
+            "INSERT OR IGNORE INTO {table_name}_dependencies (name, depends_on) VALUES (:name, :depends_on)"
+        );
+        for dependency in depends_on {
+            self.execute(
+                &query,
+                vec![
+                    (":name", task_id.into()),
+                    (":depends_on", dependency.as_str().into()),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn read_schedule_rows(
+        &self,
+        statement: &mut sqlite::Statement,
+    ) -> Result<Vec<ScheduleState>, RegistryError> {
         statement
-            .bind_iter::<_, (usize, sqlite::Value)>(
+            .iter()
+            .map(|row_result| deserialise_schedule_state(&Vec::from(row_result?)))
+            .collect()
+    }
+
+    fn get_schedule(&self, schedule_id: &str) -> Result<ScheduleState, RegistryError> {
+        let table_name = &self.table_name;
+        let columns = SCHEDULE_COLUMNS.join(", ");
+        let query = format!("SELECT {columns} FROM {table_name}_schedules WHERE id = ?");
+        let mut statement = self.connection.prepare(query)?;
+        statement.bind((1, schedule_id))?;
+        match self.read_schedule_rows(&mut statement)?.pop() {
+            Some(schedule_state) => Ok(schedule_state),
+            None => Err(RegistryError::ScheduleNotFound {
+                schedule_id: schedule_id.to_string(),
+            }),
+        }
+    }
+
+    fn get_task(&self, task_id: &str) -> Result<TaskState, RegistryError> {
+        let table_name = &self.table_name;
+        let columns = COLUMNS.join(", ");
+        let query = format!("SELECT {columns} FROM {table_name} WHERE name = ?");
+        let mut statement = self.connection.prepare(query)?;
+        statement.bind((1, task_id))?;
+        match self.read_task_rows(&mut statement)?.pop() {
+            Some((task_state, _)) => Ok(task_state),
+            None => Err(RegistryError::NotFound {
+                task_id: task_id.to_string(),
+            }),
+        }
+    }
+}
+
+impl task_registry::TaskRegistry for TaskRegistrySqlite {
+    fn get_task(&self, task_id: &str) -> Result<TaskState, RegistryError> {
+        self.read(|tables| tables.get_task(task_id))
+    }
+
+    fn update_task_from_control_loop(
+        &self,
+        task_id: &str,
+        status: TaskStatus,
+    ) -> Result<(), RegistryError> {
+        self.write(|tables| {
+            tables.transition(task_id, status, "", Vec::new())?;
+            tables.add_event(task_id, TaskEventKind::StatusChanged, None)
+        })
+    }
+
+    fn fail_task(
+        &self,
+        task_id: &str,
+        status: TaskStatus,
+        error: &TaskError,
+    ) -> Result<(), RegistryError> {
+        self.write(|tables| {
+            tables.transition(
+                task_id,
+                status,
+                ", error = :error",
+                vec![(":error", serialise_task_error(error).into())],
+            )?;
+            tables.add_event(
+                task_id,
+                TaskEventKind::StatusChanged,
+                Some(error.to_string()),
+            )
+        })
+    }
+
+    fn claim_task(&self, task_id: &str) -> Result<bool, RegistryError> {
+        self.write(|tables| {
+            let table_name = &tables.table_name;
+            let query = format!(
+                "UPDATE {table_name} SET status = :queued, attempt = attempt + 1, next_attempt_at = NULL, queued_at = :now WHERE name = :name AND status = :pending"
+            );
+            let changed = tables.execute(
+                &query,
+                vec![
+                    (":queued", TaskStatus::QUEUED.to_string().into()),
+                    (":now", serialise_timestamp(timestamp_now()).into()),
+                    (":name", task_id.into()),
+                    (":pending", TaskStatus::PENDING.to_string().into()),
+                ],
+            )?;
+            if changed == 0 {
+                // Not claimable, unless it does not exist at all
+                tables.get_status(task_id)?;
+                return Ok(false);
+            }
+            tables.add_event(task_id, TaskEventKind::Claimed, None)?;
+            Ok(true)
+        })
+    }
+
+    fn record_attempt_error(
+        &self,
+        task_id: &str,
+        attempt_error: &AttemptError,
+    ) -> Result<(), RegistryError> {
+        self.write(|tables| {
+            let table_name = &tables.table_name;
+            let query = format!(
+                "INSERT INTO {table_name}_attempt_errors (name, attempt, kind, message) SELECT :name, :attempt, :kind, :message WHERE EXISTS (SELECT 1 FROM {table_name} WHERE name = :name)"
+            );
+            tables.execute_for_task(
+                task_id,
+                &query,
+                vec![
+                    (":name", task_id.into()),
+                    (":attempt", (attempt_error.attempt as i64).into()),
+                    (":kind", attempt_error.kind.as_str().into()),
+                    (":message", attempt_error.message.as_str().into()),
+                ],
+            )?;
+            tables.add_event(
+                task_id,
+                TaskEventKind::AttemptFailed,
+                Some(format!(
+                    "attempt {} failed with {}: {}",
+                    attempt_error.attempt, attempt_error.kind, attempt_error.message
+                )),
+            )
+        })
+    }
+
+    fn schedule_retry(
+        &self,
+        task_id: &str,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<(), RegistryError> {
+        self.write(|tables| {
+            tables.transition(
+                task_id,
+                TaskStatus::PENDING,
+                ", next_attempt_at = :next_attempt_at",
+                vec![(
+                    ":next_attempt_at",
+                    serialise_timestamp(next_attempt_at).into(),
+                )],
+            )?;
+            tables.add_event(
+                task_id,
+                TaskEventKind::RetryScheduled,
+                Some(format!("next attempt at {next_attempt_at}")),
+            )
+        })
+    }
+
+    fn set_task_output(&self, task_id: &str, output: &TaskOutput) -> Result<(), RegistryError> {
+        self.write(|tables| {
+            let table_name = &tables.table_name;
+            let query = format!(
+                "UPDATE {table_name} SET exit_code = :exit_code, stdout = :stdout, stderr = :stderr WHERE name = :name"
+            );
+            tables.execute_for_task(
+                task_id,
+                &query,
+                vec![
+                    (
+                        ":exit_code",
+                        optional_value(output.exit_code.map(|x| x as i64)),
+                    ),
+                    (":stdout", output.stdout.as_str().into()),
+                    (":stderr", output.stderr.as_str().into()),
+                    (":name", task_id.into()),
+                ],
+            )
+        })
+    }
+
+    fn create_task(&self, new_task_info: &NewTaskInfo) -> Result<TaskState, RegistryError> {
+        self.write(|tables| {
+            let task_state = TaskState::new(new_task_info);
+            let table_name = &tables.table_name;
+            let columns = COLUMNS.join(", ");
+            let placeholders = COLUMNS.map(|x| format!(":{x}"));
+            let query = format!(
+                "INSERT INTO {table_name} ({columns}) VALUES ({})",
+                placeholders.join(", ")
+            );
+            let bindings = placeholders
+                .iter()
+                .map(|x| x.as_str())
+                .zip(serialise_task_state(&task_state));
+            let mut statement = tables.connection.prepare(query)?;
+            statement.bind_iter(bindings)?;
+            match statement.next() {
+                Ok(_) => {}
+                Err(error) if error.code == Some(SQLITE_CONSTRAINT) => {
+                    return Err(RegistryError::Conflict {
+                        task_id: task_state.name,
+                    })
+                }
+                Err(error) => return Err(error.into()),
+            }
+            tables.add_dependencies(&task_state.name, &task_state.depends_on)?;
+            tables.add_event(&task_state.name, TaskEventKind::Created, None)?;
+            // Read back so that duplicate dependencies are dropped as in storage
+            tables.get_task(&task_state.name)
+        })
+    }
+
+    fn get_tasks<'a>(
+        &'a self,
+        statuses: &'a HashSet<TaskStatus>,
+    ) -> Result<Box<dyn Iterator<Item = TaskState> + 'a>, RegistryError> {
+        let task_states = self.read(|tables| {
+            let statuses_vec = Vec::from_iter(statuses.iter().map(|x| x.to_string()));
+            let question_marks =
+                Vec::from_iter(statuses.iter().map(|_x| "?".to_string())).join(", ");
+            let table_name = &tables.table_name;
+            let columns = COLUMNS.join(", ");
+            let query =
+                format!("SELECT {columns} FROM {table_name} WHERE status in ({question_marks})");
+            let mut statement = tables.connection.prepare(query)?;
+            statement.bind_iter::<_, (usize, sqlite::Value)>(
                 statuses_vec
                     .iter()
                     .enumerate()
                     .map(|(i, x)| (i + 1, sqlite::Value::String(x.to_string()))),
-            )
-            .unwrap();
-        let cursor = statement.iter();
-        let my_iter = cursor.map(|row_result| {
-            let row = row_result.unwrap();
-            let values = Vec::<sqlite::Value>::from(row);
-            let serialised_task_state: SerialisedTaskState = (
-                extract_string(&values[0]),
-                extract_string(&values[1]),
-                extract_i64(&values[2]),
-                extract_string(&values[3]),
-                extract_string(&values[4]),
+            )?;
+            tables.read_task_rows(&mut statement)
+        })?;
+        Ok(Box::new(
+            task_states.into_iter().map(|(task_state, _)| task_state),
+        ))
+    }
+
+    fn get_due_tasks<'a>(
+        &'a self,
+        now: DateTime<Utc>,
+    ) -> Result<Box<dyn Iterator<Item = TaskState> + 'a>, RegistryError> {
+        let task_states = self.read(|tables| {
+            let table_name = &tables.table_name;
+            let columns = COLUMNS.join(", ");
+            let query = format!(
+                "SELECT {columns} FROM {table_name} WHERE status = :scheduled AND run_at <= :now ORDER BY run_at"
             );
-            deserialise_task_state(serialised_task_state)
-        });
-        Box::new(my_iter.collect::<Vec<_>>().into_iter())
+            let mut statement = tables.connection.prepare(query)?;
+            statement.bind_iter::<_, (_, sqlite::Value)>([
+                (":scheduled", TaskStatus::SCHEDULED.to_string().into()),
+                (":now", serialise_timestamp(now).into()),
+            ])?;
+            tables.read_task_rows(&mut statement)
+        })?;
+        Ok(Box::new(
+            task_states.into_iter().map(|(task_state, _)| task_state),
+        ))
+    }
+
+    fn get_task_page(&self, query: &TaskQuery) -> Result<TaskPage, RegistryError> {
+        self.read(|tables| {
+            let table_name = &tables.table_name;
+            let columns = COLUMNS.join(", ");
+            // Keyset pagination: the cursor is the sort key of the last row
+            // returned. The rowid breaks ties between tasks created in the
+            // same millisecond.
+            let sort_columns = match query.sort {
+                TaskSort::CreatedAt => "created_at, rowid",
+                TaskSort::Name => "name",
+            };
+            let mut conditions = Vec::new();
+            let mut bindings: Vec<(String, sqlite::Value)> = Vec::new();
+            if !query.statuses.is_empty() {
+                let mut placeholders = Vec::new();
+                for (i, status) in query.statuses.iter().enumerate() {
+                    placeholders.push(format!(":status{i}"));
+                    bindings.push((format!(":status{i}"), status.to_string().into()));
+                }
+                conditions.push(format!("status IN ({})", placeholders.join(", ")));
+            }
+            if let Some(created_after) = query.created_after {
+                conditions.push("created_at > :created_after".to_string());
+                bindings.push((
+                    ":created_after".to_string(),
+                    serialise_timestamp(created_after).into(),
+                ));
+            }
+            if let Some(finished_after) = query.finished_after {
+                conditions.push("finished_at > :finished_after".to_string());
+                bindings.push((
+                    ":finished_after".to_string(),
+                    serialise_timestamp(finished_after).into(),
+                ));
+            }
+            if let Some(queue) = &query.queue {
+                conditions.push("queue = :queue".to_string());
+                bindings.push((":queue".to_string(), queue.as_str().into()));
+            }
+            if let Some(cursor) = &query.cursor {
+                match query.sort {
+                    TaskSort::CreatedAt => {
+                        let (created_at, rowid) = parse_created_at_cursor(cursor)?;
+                        conditions.push(
+                            "(created_at > :cursor_created_at OR (created_at = :cursor_created_at AND rowid > :cursor_rowid))"
+                                .to_string(),
+                        );
+                        bindings.push((":cursor_created_at".to_string(), created_at.into()));
+                        bindings.push((":cursor_rowid".to_string(), rowid.into()));
+                    }
+                    TaskSort::Name => {
+                        conditions.push("name > :cursor".to_string());
+                        bindings.push((":cursor".to_string(), cursor.as_str().into()));
+                    }
+                }
+            }
+            let where_clause = if conditions.is_empty() {
+                String::new()
+            } else {
+                format!("WHERE {}", conditions.join(" AND "))
+            };
+            // One extra row tells whether there is another page
+            bindings.push((":limit".to_string(), (query.limit as i64 + 1).into()));
+            let sql = format!(
+                "SELECT {columns}, {sort_columns} FROM {table_name} {where_clause} ORDER BY {sort_columns} LIMIT :limit"
+            );
+            let mut statement = tables.connection.prepare(sql)?;
+            statement.bind_iter(
+                bindings
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.clone())),
+            )?;
+            let mut rows = tables.read_task_rows(&mut statement)?;
+            let next_cursor = if rows.len() > query.limit {
+                rows.truncate(query.limit);
+                match rows.last().map(|(_, sort_key)| sort_key.as_slice()) {
+                    Some([created_at, rowid]) => Some(created_at_cursor(
+                        extract_i64(created_at)?,
+                        extract_i64(rowid)?,
+                    )),
+                    Some([name]) => Some(extract_string(name)?),
+                    _ => None,
+                }
+            } else {
+                None
+            };
+            Ok(TaskPage {
+                tasks: rows.into_iter().map(|(task_state, _)| task_state).collect(),
+                next_cursor,
+            })
+        })
+    }
+
+    fn get_task_events(&self, task_id: &str) -> Result<Vec<TaskEvent>, RegistryError> {
+        self.read(|tables| {
+            tables.get_status(task_id)?;
+            let table_name = &tables.table_name;
+            let query = format!(
+                "SELECT at, kind, status, message FROM {table_name}_events WHERE name = ? ORDER BY rowid"
+            );
+            let mut statement = tables.connection.prepare(query)?;
+            statement.bind((1, task_id))?;
+            statement
+                .iter()
+                .map(|row_result| {
+                    let values = Vec::<sqlite::Value>::from(row_result?);
+                    let kind = extract_string(&values[1])?;
+                    let status = extract_string(&values[2])?;
+                    Ok(TaskEvent {
+                        at: deserialise_timestamp(extract_i64(&values[0])?)?,
+                        kind: TaskEventKind::from_str(&kind)
+                            .map_err(|_| corrupt_row(format!("unknown event kind {kind}")))?,
+                        status: TaskStatus::from_str(&status)
+                            .map_err(|_| corrupt_row(format!("unknown status {status}")))?,
+                        message: extract_optional_string(&values[3])?,
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn create_schedule(
+        &self,
+        new_schedule_info: &NewScheduleInfo,
+    ) -> Result<ScheduleState, RegistryError> {
+        self.write(|tables| {
+            let schedule_state = ScheduleState::new(new_schedule_info);
+            let table_name = &tables.table_name;
+            let columns = SCHEDULE_COLUMNS.join(", ");
+            let placeholders = SCHEDULE_COLUMNS.map(|x| format!(":{x}"));
+            let query = format!(
+                "INSERT INTO {table_name}_schedules ({columns}) VALUES ({})",
+                placeholders.join(", ")
+            );
+            let bindings = placeholders
+                .iter()
+                .map(|x| x.as_str())
+                .zip(serialise_schedule_state(&schedule_state));
+            let mut statement = tables.connection.prepare(query)?;
+            statement.bind_iter(bindings)?;
+            match statement.next() {
+                Ok(_) => Ok(schedule_state),
+                Err(error) if error.code == Some(SQLITE_CONSTRAINT) => {
+                    Err(RegistryError::ScheduleConflict {
+                        schedule_id: schedule_state.id,
+                    })
+                }
+                Err(error) => Err(error.into()),
+            }
+        })
+    }
+
+    fn get_schedule(&self, schedule_id: &str) -> Result<ScheduleState, RegistryError> {
+        self.read(|tables| tables.get_schedule(schedule_id))
+    }
+
+    fn get_schedules(&self) -> Result<Vec<ScheduleState>, RegistryError> {
+        self.read(|tables| {
+            let table_name = &tables.table_name;
+            let columns = SCHEDULE_COLUMNS.join(", ");
+            let query = format!("SELECT {columns} FROM {table_name}_schedules ORDER BY rowid");
+            let mut statement = tables.connection.prepare(query)?;
+            tables.read_schedule_rows(&mut statement)
+        })
+    }
+
+    fn delete_schedule(&self, schedule_id: &str) -> Result<(), RegistryError> {
+        self.write(|tables| {
+            let table_name = &tables.table_name;
+            let query = format!("DELETE FROM {table_name}_schedules WHERE id = :id");
+            match tables.execute(&query, vec![(":id", schedule_id.into())])? {
+                0 => Err(RegistryError::ScheduleNotFound {
+                    schedule_id: schedule_id.to_string(),
+                }),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn record_schedule_run(
+        &self,
+        schedule_id: &str,
+        run_at: DateTime<Utc>,
+        task_id: Option<&str>,
+    ) -> Result<(), RegistryError> {
+        self.write(|tables| {
+            let table_name = &tables.table_name;
+            // A skipped run leaves the task of the previous run as the latest
+            let query = format!(
+                "UPDATE {table_name}_schedules SET last_run_at = :run_at, last_task_id = COALESCE(:task_id, last_task_id) WHERE id = :id"
+            );
+            let bindings = vec![
+                (":run_at", serialise_timestamp(run_at).into()),
+                (":task_id", optional_value(task_id)),
+                (":id", schedule_id.into()),
+            ];
+            match tables.execute(&query, bindings)? {
+                0 => Err(RegistryError::ScheduleNotFound {
+                    schedule_id: schedule_id.to_string(),
+                }),
+                _ => Ok(()),
+            }
+        })
     }
 }
 
@@ -173,20 +1062,44 @@ impl Drop for TaskRegistrySqlite {
     fn drop(&mut self) {
         if self.table_permanence == TablePermanance::DropOnClose {
             let table_name = &self.table_name;
-            let query = format!("DROP TABLE {table_name}");
-            let mut statement = self.connection.prepare(query).unwrap();
-            let state = statement.next().unwrap();
-            assert_eq!(state, sqlite::State::Done);
+            let connection = self
+                .writer
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner);
+            for query in [
+                format!("DROP TABLE {table_name}"),
+                format!("DROP TABLE {table_name}_dependencies"),
+                format!("DROP TABLE {table_name}_attempt_errors"),
+                format!("DROP TABLE {table_name}_events"),
+                format!("DROP TABLE {table_name}_schedules"),
+                format!("DROP TABLE {table_name}_schema_version"),
+            ] {
+                if let Err(error) = connection.execute(&query) {
+                    println!("Could not drop table: {error}");
+                }
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::core::core_types::{NewTaskInfo, TaskDefinition, TaskState, TaskStatus};
-    use crate::registry::task_registry::TaskRegistry;
+    use crate::core::core_types::{
+        timestamp_now, AttemptError, NewTaskInfo, TaskDefinition, TaskError, TaskEventKind,
+        TaskOutput, TaskState, TaskStatus,
+    };
+    use crate::core::retry_policy::{RetryBackoff, RetryPolicy};
+    use crate::core::schedule::{
+        MissedRunPolicy, NewScheduleInfo, OverlapPolicy, ScheduleDefinition,
+    };
+    use crate::registry::task_registry::{RegistryError, TaskQuery, TaskRegistry, TaskSort};
+    use crate::registry::task_registry_in_memory::TaskRegistryInMemory;
     use crate::registry::task_registry_sqlite::{TablePermanance, TaskRegistrySqlite};
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
     use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
 
     use rstest::*;
 
@@ -196,13 +1109,17 @@ mod tests {
     #[derive(PartialEq)]
     enum RegistryType {
         Sqlite,
+        InMemory,
     }
 
     fn make_registry(registry_type: RegistryType) -> Box<dyn TaskRegistry> {
         if registry_type == RegistryType::Sqlite {
             let registry =
-                TaskRegistrySqlite::new(DATABASE_NAME, TABLE_NAME, TablePermanance::DropOnClose);
+                TaskRegistrySqlite::new(DATABASE_NAME, TABLE_NAME, TablePermanance::DropOnClose)
+                    .unwrap();
             Box::new(registry)
+        } else if registry_type == RegistryType::InMemory {
+            Box::new(TaskRegistryInMemory::new())
         } else {
             panic!("Unknown registry type")
         }
@@ -210,109 +1127,1044 @@ mod tests {
 
     #[rstest]
     #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
     fn get_and_write_to_database(#[case] registry_type: RegistryType) {
         let mut registry_box = make_registry(registry_type);
         let registry = registry_box.as_mut();
         let task_definition1 = TaskDefinition {
-            message: "hello from task 1".to_string(),
-            sleep_time_seconds: 4,
-            output_path: "dummy-path".to_string(),
+            kind: "sleep_and_write".to_string(),
+            parameters: json!({
+                "message": "hello from task 1",
+                "sleep_time_seconds": 4,
+                "output_path": "dummy-path",
+            }),
+            ..Default::default()
         };
         let task_definition2 = TaskDefinition {
-            message: "hello from task 2".to_string(),
-            sleep_time_seconds: 6,
-            output_path: "dummy-path".to_string(),
+            kind: "sleep_and_write".to_string(),
+            parameters: json!({
+                "message": "hello from task 2",
+                "sleep_time_seconds": 6,
+                "output_path": "dummy-path",
+            }),
+            ..Default::default()
         };
         let task1_id = "Task 1";
         let task2_id = "Task 2";
-        registry.create_task(&NewTaskInfo {
-            task_id: task1_id.to_string(),
-            task_definition: task_definition1.clone(),
-        });
-        registry.create_task(&NewTaskInfo {
-            task_id: task2_id.to_string(),
-            task_definition: task_definition2.clone(),
-        });
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: task1_id.to_string(),
+                task_definition: task_definition1.clone(),
+            })
+            .unwrap();
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: task2_id.to_string(),
+                task_definition: task_definition2.clone(),
+            })
+            .unwrap();
         let retrieved_task1 = registry.get_task(task1_id).unwrap();
         let retrieved_task2 = registry.get_task(task2_id).unwrap();
         assert_eq!(
-            TaskState::new(&NewTaskInfo {
-                task_id: task1_id.to_string(),
-                task_definition: task_definition1
-            }),
+            TaskState {
+                created_at: retrieved_task1.created_at,
+                ..TaskState::new(&NewTaskInfo {
+                    task_id: task1_id.to_string(),
+                    task_definition: task_definition1
+                })
+            },
             retrieved_task1
         );
         assert_eq!(
-            TaskState::new(&NewTaskInfo {
-                task_id: task2_id.to_string(),
-                task_definition: task_definition2
-            }),
+            TaskState {
+                created_at: retrieved_task2.created_at,
+                ..TaskState::new(&NewTaskInfo {
+                    task_id: task2_id.to_string(),
+                    task_definition: task_definition2
+                })
+            },
             retrieved_task2
         );
     }
 
     #[rstest]
     #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
     fn update_element_in_database(#[case] registry_type: RegistryType) {
         let mut registry_box = make_registry(registry_type);
         let registry = registry_box.as_mut();
         let task_definition = TaskDefinition {
-            message: "hello from task 1".to_string(),
-            sleep_time_seconds: 4,
-            output_path: "dummy-path".to_string(),
+            kind: "sleep_and_write".to_string(),
+            parameters: json!({
+                "message": "hello from task 1",
+                "sleep_time_seconds": 4,
+                "output_path": "dummy-path",
+            }),
+            ..Default::default()
         };
         let task_id = "my task";
-        registry.create_task(&NewTaskInfo {
-            task_id: task_id.to_string(),
-            task_definition: task_definition,
-        });
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: task_id.to_string(),
+                task_definition,
+            })
+            .unwrap();
         let retrieved_task = registry.get_task(task_id).unwrap();
         assert_eq!(retrieved_task.status, TaskStatus::PENDING);
-        registry.update_task_from_control_loop(task_id, TaskStatus::RUNNING);
+        assert!(registry.claim_task(task_id).unwrap());
+        registry
+            .update_task_from_control_loop(task_id, TaskStatus::RUNNING)
+            .unwrap();
         let retrieved_task_after_update = registry.get_task(task_id).unwrap();
         assert_eq!(retrieved_task_after_update.status, TaskStatus::RUNNING);
     }
 
     #[rstest]
     #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
     fn list_by_statuses(#[case] registry_type: RegistryType) {
         let mut registry_box = make_registry(registry_type);
         let registry = registry_box.as_mut();
         let task_definition1 = TaskDefinition {
-            message: "hello from task 1".to_string(),
-            sleep_time_seconds: 4,
-            output_path: "dummy-path".to_string(),
+            kind: "sleep_and_write".to_string(),
+            parameters: json!({
+                "message": "hello from task 1",
+                "sleep_time_seconds": 4,
+                "output_path": "dummy-path",
+            }),
+            ..Default::default()
         };
         let task_definition2 = TaskDefinition {
-            message: "hello from task 2".to_string(),
-            sleep_time_seconds: 6,
-            output_path: "dummy-path".to_string(),
+            kind: "sleep_and_write".to_string(),
+            parameters: json!({
+                "message": "hello from task 2",
+                "sleep_time_seconds": 6,
+                "output_path": "dummy-path",
+            }),
+            ..Default::default()
         };
         let task1_id = "Task 1";
         let task2_id = "Task 2";
-        registry.create_task(&NewTaskInfo {
-            task_id: task1_id.to_string(),
-            task_definition: task_definition1.clone(),
-        });
-        registry.create_task(&NewTaskInfo {
-            task_id: task2_id.to_string(),
-            task_definition: task_definition2.clone(),
-        });
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: task1_id.to_string(),
+                task_definition: task_definition1.clone(),
+            })
+            .unwrap();
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: task2_id.to_string(),
+                task_definition: task_definition2.clone(),
+            })
+            .unwrap();
         let mut statuses = HashSet::new();
         statuses.insert(TaskStatus::PENDING);
-        let tasks_iter = registry.get_tasks(&statuses);
+        let tasks_iter = registry.get_tasks(&statuses).unwrap();
         let mut tasks = Vec::from_iter(tasks_iter);
         tasks.sort_by(|a, b| a.name.to_string().cmp(&b.name));
         let expected_tasks = vec![
-            TaskState::new(&NewTaskInfo {
-                task_id: task1_id.to_string(),
-                task_definition: task_definition1,
-            }),
-            TaskState::new(&NewTaskInfo {
-                task_id: task2_id.to_string(),
-                task_definition: task_definition2,
-            }),
+            TaskState {
+                created_at: tasks[0].created_at,
+                ..TaskState::new(&NewTaskInfo {
+                    task_id: task1_id.to_string(),
+                    task_definition: task_definition1,
+                })
+            },
+            TaskState {
+                created_at: tasks[1].created_at,
+                ..TaskState::new(&NewTaskInfo {
+                    task_id: task2_id.to_string(),
+                    task_definition: task_definition2,
+                })
+            },
         ];
         assert_eq!(tasks, expected_tasks);
     }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn store_task_output(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        let task_id = "shell task";
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: task_id.to_string(),
+                task_definition: TaskDefinition {
+                    kind: "shell_command".to_string(),
+                    parameters: json!({"program": "false"}),
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        assert_eq!(registry.get_task(task_id).unwrap().output, None);
+        let output = TaskOutput {
+            exit_code: Some(1),
+            stdout: "some output".to_string(),
+            stderr: "some error".to_string(),
+        };
+        registry.set_task_output(task_id, &output).unwrap();
+        assert_eq!(registry.get_task(task_id).unwrap().output, Some(output));
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn store_task_error(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        let task_id = "failing task";
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: task_id.to_string(),
+                task_definition: TaskDefinition {
+                    kind: "shell_command".to_string(),
+                    parameters: json!({"program": "false"}),
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        assert!(registry.claim_task(task_id).unwrap());
+        registry
+            .update_task_from_control_loop(task_id, TaskStatus::RUNNING)
+            .unwrap();
+        assert_eq!(registry.get_task(task_id).unwrap().error, None);
+        let error = TaskError {
+            exit_code: Some(1),
+            backtrace: Some("somewhere".to_string()),
+            ..TaskError::new("exit_code:1", "process exited with 1")
+        };
+        registry
+            .fail_task(task_id, TaskStatus::FAILED, &error)
+            .unwrap();
+        let task_state = registry.get_task(task_id).unwrap();
+        assert_eq!(task_state.status, TaskStatus::FAILED);
+        assert!(task_state.finished_at.is_some());
+        assert_eq!(task_state.error, Some(error.clone()));
+        assert_eq!(
+            registry
+                .get_task_events(task_id)
+                .unwrap()
+                .pop()
+                .unwrap()
+                .message,
+            Some("exit_code:1: process exited with 1".to_string())
+        );
+
+        // Same state machine as any other status change
+        assert!(matches!(
+            registry.fail_task(task_id, TaskStatus::FAILED, &error),
+            Err(RegistryError::InvalidTransition { .. })
+        ));
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn claim_task_only_once(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        let task_id = "my task";
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: task_id.to_string(),
+                task_definition: TaskDefinition {
+                    kind: "sleep_and_write".to_string(),
+                    parameters: json!({}),
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        assert!(registry.claim_task(task_id).unwrap());
+        assert_eq!(
+            registry.get_task(task_id).unwrap().status,
+            TaskStatus::QUEUED
+        );
+        assert!(!registry.claim_task(task_id).unwrap());
+        assert_eq!(
+            registry.claim_task("unknown task"),
+            Err(RegistryError::NotFound {
+                task_id: "unknown task".to_string()
+            })
+        );
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn store_dependencies(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        let new_task = |task_id: &str, depends_on: &[&str]| NewTaskInfo {
+            task_id: task_id.to_string(),
+            task_definition: TaskDefinition {
+                kind: "sleep_and_write".to_string(),
+                parameters: json!({}),
+                depends_on: depends_on.iter().map(|x| x.to_string()).collect(),
+                ..Default::default()
+            },
+        };
+        registry.create_task(&new_task("a", &[])).unwrap();
+        registry.create_task(&new_task("b", &["a"])).unwrap();
+        registry
+            .create_task(&new_task("c", &["b", "a", "not yet submitted"]))
+            .unwrap();
+        assert_eq!(
+            registry.get_task("c").unwrap().depends_on,
+            vec!["b", "a", "not yet submitted"]
+        );
+        let pending = HashSet::from([TaskStatus::PENDING]);
+        let b = registry
+            .get_tasks(&pending)
+            .unwrap()
+            .find(|x| x.name == "b")
+            .unwrap();
+        assert_eq!(b.depends_on, vec!["a"]);
+
+        assert!(!registry
+            .creates_dependency_cycle("d", &["c".to_string()])
+            .unwrap());
+        assert!(registry
+            .creates_dependency_cycle("d", &["d".to_string()])
+            .unwrap());
+        assert!(!registry
+            .creates_dependency_cycle("not yet submitted", &["a".to_string()])
+            .unwrap());
+        assert!(registry
+            .creates_dependency_cycle("not yet submitted", &["c".to_string()])
+            .unwrap());
+        assert!(registry
+            .creates_dependency_cycle("a", &["e".to_string(), "c".to_string()])
+            .unwrap());
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn track_attempts_and_retries(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        let task_id = "flaky task";
+        let retry_policy = RetryPolicy {
+            max_attempts: 3,
+            retry_backoff: RetryBackoff::Fixed { delay_seconds: 5.0 },
+            retry_on: vec!["timed_out".to_string()],
+        };
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: task_id.to_string(),
+                task_definition: TaskDefinition {
+                    kind: "sleep_and_write".to_string(),
+                    parameters: json!({}),
+                    retry_policy: retry_policy.clone(),
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        assert!(registry.claim_task(task_id).unwrap());
+        let attempt_error = AttemptError {
+            attempt: 1,
+            kind: "timed_out".to_string(),
+            message: "took too long".to_string(),
+        };
+        registry
+            .record_attempt_error(task_id, &attempt_error)
+            .unwrap();
+        let next_attempt_at = Utc.timestamp_millis_opt(1_700_000_000_123).unwrap();
+        registry.schedule_retry(task_id, next_attempt_at).unwrap();
+
+        let task_state = registry.get_task(task_id).unwrap();
+        assert_eq!(task_state.status, TaskStatus::PENDING);
+        assert_eq!(task_state.attempt, 1);
+        assert_eq!(task_state.next_attempt_at, Some(next_attempt_at));
+        assert_eq!(task_state.retry_policy, retry_policy);
+        assert_eq!(task_state.attempt_errors, vec![attempt_error]);
+
+        assert!(registry.claim_task(task_id).unwrap());
+        let task_state = registry.get_task(task_id).unwrap();
+        assert_eq!(task_state.attempt, 2);
+        assert_eq!(task_state.next_attempt_at, None);
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn list_tasks_in_pages(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        for task_id in ["c", "a", "d", "b", "e"] {
+            registry
+                .create_task(&NewTaskInfo {
+                    task_id: task_id.to_string(),
+                    task_definition: TaskDefinition {
+                        kind: "shell_command".to_string(),
+                        parameters: json!({"program": "true"}),
+                        ..Default::default()
+                    },
+                })
+                .unwrap();
+        }
+        assert!(registry.claim_task("d").unwrap());
+
+        let page_names = |query: &TaskQuery| -> (Vec<String>, Option<String>) {
+            let page = registry.get_task_page(query).unwrap();
+            let names = page.tasks.into_iter().map(|task| task.name).collect();
+            (names, page.next_cursor)
+        };
+        let mut query = TaskQuery {
+            statuses: HashSet::new(),
+            sort: TaskSort::CreatedAt,
+            limit: 2,
+            cursor: None,
+            ..Default::default()
+        };
+        let mut names = Vec::new();
+        loop {
+            let (page, next_cursor) = page_names(&query);
+            assert!(page.len() <= 2);
+            names.extend(page);
+            if next_cursor.is_none() {
+                break;
+            }
+            query.cursor = next_cursor;
+        }
+        assert_eq!(names, vec!["c", "a", "d", "b", "e"]);
+
+        let query = TaskQuery {
+            statuses: HashSet::from([TaskStatus::PENDING]),
+            sort: TaskSort::Name,
+            limit: 3,
+            cursor: None,
+            ..Default::default()
+        };
+        let (page, next_cursor) = page_names(&query);
+        assert_eq!(page, vec!["a", "b", "c"]);
+        assert_eq!(next_cursor, Some("c".to_string()));
+        let (page, next_cursor) = page_names(&TaskQuery {
+            cursor: next_cursor,
+            ..query.clone()
+        });
+        assert_eq!(page, vec!["e"]);
+        assert_eq!(next_cursor, None);
+
+        assert!(registry
+            .get_task_page(&TaskQuery {
+                sort: TaskSort::CreatedAt,
+                cursor: Some("not a cursor".to_string()),
+                ..query
+            })
+            .is_err());
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn list_tasks_with_related_rows(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        for (task_id, depends_on) in [
+            ("related a", vec![]),
+            ("related b", vec!["related a"]),
+            ("related c", vec!["related b", "related a"]),
+        ] {
+            registry
+                .create_task(&NewTaskInfo {
+                    task_id: task_id.to_string(),
+                    task_definition: TaskDefinition {
+                        kind: "shell_command".to_string(),
+                        parameters: json!({"program": "true"}),
+                        depends_on: depends_on.iter().map(|x| x.to_string()).collect(),
+                        ..Default::default()
+                    },
+                })
+                .unwrap();
+        }
+        for (task_id, attempt) in [("related c", 2), ("related a", 1), ("related c", 1)] {
+            registry
+                .record_attempt_error(
+                    task_id,
+                    &AttemptError {
+                        attempt,
+                        kind: "other".to_string(),
+                        message: format!("{task_id} attempt {attempt}"),
+                    },
+                )
+                .unwrap();
+        }
+
+        let page = registry
+            .get_task_page(&TaskQuery {
+                limit: 10,
+                ..Default::default()
+            })
+            .unwrap();
+        let related: Vec<(Vec<String>, Vec<u32>)> = page
+            .tasks
+            .into_iter()
+            .map(|task_state| {
+                let attempts = task_state.attempt_errors.iter().map(|x| x.attempt);
+                (task_state.depends_on, attempts.collect())
+            })
+            .collect();
+        assert_eq!(
+            related,
+            vec![
+                (vec![], vec![1]),
+                (vec!["related a".to_string()], vec![]),
+                (
+                    vec!["related b".to_string(), "related a".to_string()],
+                    vec![1, 2]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn list_tasks_in_created_at_order() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", TABLE_NAME, TablePermanance::DropOnClose).unwrap();
+        for task_id in ["created first", "created second", "created third"] {
+            registry
+                .create_task(&NewTaskInfo {
+                    task_id: task_id.to_string(),
+                    task_definition: TaskDefinition {
+                        kind: "shell_command".to_string(),
+                        parameters: json!({"program": "true"}),
+                        ..Default::default()
+                    },
+                })
+                .unwrap();
+        }
+        // E.g. after the clock was set back, the rowid no longer follows
+        // created_at
+        let created_at = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        registry
+            .writer
+            .lock()
+            .unwrap()
+            .execute(format!(
+                "UPDATE {TABLE_NAME} SET created_at = {} WHERE name = 'created third'",
+                created_at.timestamp_millis()
+            ))
+            .unwrap();
+
+        let mut query = TaskQuery {
+            limit: 1,
+            ..Default::default()
+        };
+        let mut names = Vec::new();
+        loop {
+            let page = registry.get_task_page(&query).unwrap();
+            names.extend(page.tasks.into_iter().map(|task| task.name));
+            if page.next_cursor.is_none() {
+                break;
+            }
+            query.cursor = page.next_cursor;
+        }
+        assert_eq!(
+            names,
+            vec!["created third", "created first", "created second"]
+        );
+
+        let page = registry
+            .get_task_page(&TaskQuery {
+                created_after: Some(created_at),
+                limit: 10,
+                ..Default::default()
+            })
+            .unwrap();
+        let names: Vec<String> = page.tasks.into_iter().map(|task| task.name).collect();
+        assert_eq!(names, vec!["created first", "created second"]);
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn filter_tasks_by_queue(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        for (task_id, queue) in [
+            ("io 1", Some("io")),
+            ("cpu", Some("cpu")),
+            ("none", None),
+            ("io 2", Some("io")),
+        ] {
+            registry
+                .create_task(&NewTaskInfo {
+                    task_id: task_id.to_string(),
+                    task_definition: TaskDefinition {
+                        kind: "shell_command".to_string(),
+                        parameters: json!({"program": "true"}),
+                        queue: queue.map(|x| x.to_string()),
+                        ..Default::default()
+                    },
+                })
+                .unwrap();
+        }
+        assert_eq!(
+            registry.get_task("io 1").unwrap().queue,
+            Some("io".to_string())
+        );
+        assert_eq!(registry.get_task("none").unwrap().queue, None);
+
+        let page = registry
+            .get_task_page(&TaskQuery {
+                queue: Some("io".to_string()),
+                limit: 10,
+                ..Default::default()
+            })
+            .unwrap();
+        let names: Vec<String> = page.tasks.into_iter().map(|x| x.name).collect();
+        assert_eq!(names, vec!["io 1", "io 2"]);
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn select_due_tasks(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        let now = timestamp_now();
+        let hour = chrono::Duration::hours(1);
+        for (task_id, run_at) in [
+            ("scheduled late", Some(now + hour * 2)),
+            ("scheduled early", Some(now + hour)),
+            ("scheduled past", Some(now - hour)),
+            ("scheduled never", None),
+        ] {
+            registry
+                .create_task(&NewTaskInfo {
+                    task_id: task_id.to_string(),
+                    task_definition: TaskDefinition {
+                        kind: "shell_command".to_string(),
+                        parameters: json!({"program": "true"}),
+                        run_at,
+                        ..Default::default()
+                    },
+                })
+                .unwrap();
+        }
+        let status = |task_id| registry.get_task(task_id).unwrap().status;
+        assert_eq!(status("scheduled late"), TaskStatus::SCHEDULED);
+        // Already due when submitted
+        assert_eq!(status("scheduled past"), TaskStatus::PENDING);
+        assert_eq!(status("scheduled never"), TaskStatus::PENDING);
+        assert_eq!(
+            registry.get_task("scheduled early").unwrap().run_at,
+            Some(now + hour)
+        );
+
+        let due_names = |at| -> Vec<String> {
+            registry
+                .get_due_tasks(at)
+                .unwrap()
+                .map(|task| task.name)
+                .collect()
+        };
+        assert!(due_names(now).is_empty());
+        assert_eq!(due_names(now + hour), vec!["scheduled early"]);
+        assert_eq!(
+            due_names(now + hour * 3),
+            vec!["scheduled early", "scheduled late"]
+        );
+
+        registry
+            .update_task_from_control_loop("scheduled early", TaskStatus::PENDING)
+            .unwrap();
+        assert_eq!(due_names(now + hour * 3), vec!["scheduled late"]);
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn manage_schedules(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        let new_schedule_info = |schedule_id: &str| NewScheduleInfo {
+            schedule_id: schedule_id.to_string(),
+            schedule_definition: ScheduleDefinition {
+                cron: "0 2 * * *".to_string(),
+                timezone: "Europe/London".to_string(),
+                task_definition: TaskDefinition {
+                    kind: "shell_command".to_string(),
+                    parameters: json!({"program": "true"}),
+                    queue: Some("nightly".to_string()),
+                    ..Default::default()
+                },
+                missed_runs: MissedRunPolicy::CatchUp,
+                overlap: OverlapPolicy::Wait,
+            },
+        };
+        let created = registry
+            .create_schedule(&new_schedule_info("nightly"))
+            .unwrap();
+        registry
+            .create_schedule(&new_schedule_info("hourly"))
+            .unwrap();
+        assert_eq!(
+            registry.create_schedule(&new_schedule_info("nightly")),
+            Err(RegistryError::ScheduleConflict {
+                schedule_id: "nightly".to_string()
+            })
+        );
+        assert_eq!(registry.get_schedule("nightly").unwrap(), created);
+        let ids: Vec<String> = registry
+            .get_schedules()
+            .unwrap()
+            .into_iter()
+            .map(|x| x.id)
+            .collect();
+        assert_eq!(ids, vec!["nightly", "hourly"]);
+
+        let run_at = Utc.timestamp_millis_opt(1_700_000_000_000).unwrap();
+        registry
+            .record_schedule_run("nightly", run_at, Some("nightly-1"))
+            .unwrap();
+        let skipped_at = run_at + chrono::Duration::days(1);
+        registry
+            .record_schedule_run("nightly", skipped_at, None)
+            .unwrap();
+        let schedule_state = registry.get_schedule("nightly").unwrap();
+        assert_eq!(schedule_state.last_run_at, Some(skipped_at));
+        assert_eq!(schedule_state.last_task_id, Some("nightly-1".to_string()));
+
+        registry.delete_schedule("nightly").unwrap();
+        let not_found = RegistryError::ScheduleNotFound {
+            schedule_id: "nightly".to_string(),
+        };
+        assert_eq!(registry.get_schedule("nightly"), Err(not_found.clone()));
+        assert_eq!(registry.delete_schedule("nightly"), Err(not_found.clone()));
+        assert_eq!(
+            registry.record_schedule_run("nightly", run_at, None),
+            Err(not_found)
+        );
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn reject_duplicate_task_ids(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        let new_task_info = |program: &str| NewTaskInfo {
+            task_id: "duplicate".to_string(),
+            task_definition: TaskDefinition {
+                kind: "shell_command".to_string(),
+                parameters: json!({ "program": program }),
+                depends_on: vec!["upstream".to_string()],
+                idempotency_key: Some("key".to_string()),
+                priority: 3,
+                ..Default::default()
+            },
+        };
+        let created = registry.create_task(&new_task_info("true")).unwrap();
+        assert_eq!(created.idempotency_key, Some("key".to_string()));
+        assert_eq!(created.priority, 3);
+        let error = registry.create_task(&new_task_info("false")).unwrap_err();
+        assert_eq!(
+            error,
+            RegistryError::Conflict {
+                task_id: "duplicate".to_string()
+            }
+        );
+        let task_state = registry.get_task("duplicate").unwrap();
+        assert_eq!(task_state, created);
+    }
+
+    #[test]
+    fn corrupt_rows_are_errors() {
+        let registry =
+            TaskRegistrySqlite::new(":memory:", TABLE_NAME, TablePermanance::DropOnClose).unwrap();
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: "corrupt".to_string(),
+                task_definition: TaskDefinition {
+                    kind: "shell_command".to_string(),
+                    parameters: json!({"program": "true"}),
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        registry
+            .writer
+            .lock()
+            .unwrap()
+            .execute(format!(
+                "UPDATE {TABLE_NAME} SET status = 'EXPLODED' WHERE name = 'corrupt'"
+            ))
+            .unwrap();
+        assert!(matches!(
+            registry.get_task("corrupt"),
+            Err(RegistryError::CorruptRow { .. })
+        ));
+        let query = TaskQuery {
+            statuses: HashSet::new(),
+            sort: TaskSort::Name,
+            limit: 10,
+            cursor: None,
+            ..Default::default()
+        };
+        assert!(matches!(
+            registry.get_task_page(&query),
+            Err(RegistryError::CorruptRow { .. })
+        ));
+        assert_eq!(
+            registry.update_task_from_control_loop("missing", TaskStatus::RUNNING),
+            Err(RegistryError::NotFound {
+                task_id: "missing".to_string()
+            })
+        );
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn reject_invalid_transitions(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        let task_id = "finished task";
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: task_id.to_string(),
+                task_definition: TaskDefinition {
+                    kind: "shell_command".to_string(),
+                    parameters: json!({"program": "true"}),
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        assert_eq!(
+            registry.update_task_from_control_loop(task_id, TaskStatus::SUCCESS),
+            Err(RegistryError::InvalidTransition {
+                task_id: task_id.to_string(),
+                from: TaskStatus::PENDING,
+                to: TaskStatus::SUCCESS,
+            })
+        );
+        assert!(registry.claim_task(task_id).unwrap());
+        for status in [TaskStatus::RUNNING, TaskStatus::SUCCESS] {
+            registry
+                .update_task_from_control_loop(task_id, status)
+                .unwrap();
+        }
+        // A late message from the worker must not reopen the task
+        assert_eq!(
+            registry.update_task_from_control_loop(task_id, TaskStatus::RUNNING),
+            Err(RegistryError::InvalidTransition {
+                task_id: task_id.to_string(),
+                from: TaskStatus::SUCCESS,
+                to: TaskStatus::RUNNING,
+            })
+        );
+        assert!(registry.schedule_retry(task_id, Utc::now()).is_err());
+        let task_state = registry.get_task(task_id).unwrap();
+        assert_eq!(task_state.status, TaskStatus::SUCCESS);
+        assert_eq!(task_state.next_attempt_at, None);
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn track_task_timestamps(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        let before = timestamp_now();
+        for task_id in ["timed", "untouched"] {
+            registry
+                .create_task(&NewTaskInfo {
+                    task_id: task_id.to_string(),
+                    task_definition: TaskDefinition {
+                        kind: "shell_command".to_string(),
+                        parameters: json!({"program": "true"}),
+                        ..Default::default()
+                    },
+                })
+                .unwrap();
+        }
+        let task_state = registry.get_task("timed").unwrap();
+        assert!(task_state.created_at >= before);
+        assert_eq!(task_state.queued_at, None);
+        assert_eq!(task_state.duration(), None);
+
+        assert!(registry.claim_task("timed").unwrap());
+        registry
+            .update_task_from_control_loop("timed", TaskStatus::RUNNING)
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        registry
+            .update_task_from_control_loop("timed", TaskStatus::SUCCESS)
+            .unwrap();
+        let task_state = registry.get_task("timed").unwrap();
+        let queued_at = task_state.queued_at.unwrap();
+        let started_at = task_state.started_at.unwrap();
+        let finished_at = task_state.finished_at.unwrap();
+        assert!(task_state.created_at <= queued_at);
+        assert!(queued_at <= started_at);
+        assert!(task_state.duration().unwrap() >= chrono::Duration::milliseconds(20));
+        assert_eq!(task_state.duration(), Some(finished_at - started_at));
+
+        let finished_recently = TaskQuery {
+            finished_after: Some(before - chrono::Duration::hours(1)),
+            limit: 10,
+            ..Default::default()
+        };
+        let page = registry.get_task_page(&finished_recently).unwrap();
+        let names: Vec<String> = page.tasks.into_iter().map(|x| x.name).collect();
+        assert_eq!(names, vec!["timed"]);
+        let created_later = TaskQuery {
+            created_after: Some(finished_at),
+            limit: 10,
+            ..Default::default()
+        };
+        assert!(registry
+            .get_task_page(&created_later)
+            .unwrap()
+            .tasks
+            .is_empty());
+    }
+
+    #[rstest]
+    #[case(RegistryType::Sqlite)]
+    #[case(RegistryType::InMemory)]
+    fn record_task_events(#[case] registry_type: RegistryType) {
+        let mut registry_box = make_registry(registry_type);
+        let registry = registry_box.as_mut();
+        let task_id = "audited";
+        registry
+            .create_task(&NewTaskInfo {
+                task_id: task_id.to_string(),
+                task_definition: TaskDefinition {
+                    kind: "shell_command".to_string(),
+                    parameters: json!({"program": "true"}),
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        assert!(registry.claim_task(task_id).unwrap());
+        // Claiming again changes nothing, so is not recorded
+        assert!(!registry.claim_task(task_id).unwrap());
+        registry
+            .update_task_from_control_loop(task_id, TaskStatus::RUNNING)
+            .unwrap();
+        registry
+            .record_attempt_error(
+                task_id,
+                &AttemptError {
+                    attempt: 1,
+                    kind: "exit_code".to_string(),
+                    message: "exited with 1".to_string(),
+                },
+            )
+            .unwrap();
+        let next_attempt_at = Utc.timestamp_millis_opt(1_700_000_000_123).unwrap();
+        registry.schedule_retry(task_id, next_attempt_at).unwrap();
+        assert!(registry.claim_task(task_id).unwrap());
+        registry
+            .update_task_from_control_loop(task_id, TaskStatus::RUNNING)
+            .unwrap();
+        registry
+            .update_task_from_control_loop(task_id, TaskStatus::SUCCESS)
+            .unwrap();
+
+        let events = registry.get_task_events(task_id).unwrap();
+        let kinds_and_statuses: Vec<(TaskEventKind, TaskStatus)> =
+            events.iter().map(|x| (x.kind, x.status.clone())).collect();
+        assert_eq!(
+            kinds_and_statuses,
+            vec![
+                (TaskEventKind::Created, TaskStatus::PENDING),
+                (TaskEventKind::Claimed, TaskStatus::QUEUED),
+                (TaskEventKind::StatusChanged, TaskStatus::RUNNING),
+                (TaskEventKind::AttemptFailed, TaskStatus::RUNNING),
+                (TaskEventKind::RetryScheduled, TaskStatus::PENDING),
+                (TaskEventKind::Claimed, TaskStatus::QUEUED),
+                (TaskEventKind::StatusChanged, TaskStatus::RUNNING),
+                (TaskEventKind::StatusChanged, TaskStatus::SUCCESS),
+            ]
+        );
+        assert!(events.windows(2).all(|x| x[0].at <= x[1].at));
+        assert_eq!(
+            events[3].message.as_deref(),
+            Some("attempt 1 failed with exit_code: exited with 1")
+        );
+        assert_eq!(
+            events[4].message,
+            Some(format!("next attempt at {next_attempt_at}"))
+        );
+        assert_eq!(events[0].message, None);
+
+        assert_eq!(
+            registry.get_task_events("unknown"),
+            Err(RegistryError::NotFound {
+                task_id: "unknown".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn share_between_threads() {
+        // In-memory databases have a single connection, so use a file
+        let path = std::env::temp_dir().join("task_registry_share_between_threads.db");
+        let _ = std::fs::remove_file(&path);
+        let registry: Arc<dyn TaskRegistry> = Arc::new(
+            TaskRegistrySqlite::new(
+                path.to_str().unwrap(),
+                TABLE_NAME,
+                TablePermanance::DropOnClose,
+            )
+            .unwrap(),
+        );
+        let task_id = |thread: usize, task: usize| format!("thread {thread} task {task}");
+        // Every thread creates tasks of its own and then races the others to
+        // claim all of them
+        let handles: Vec<_> = (0..4)
+            .map(|thread| {
+                let registry = registry.clone();
+                thread::spawn(move || {
+                    for task in 0..10 {
+                        registry
+                            .create_task(&NewTaskInfo {
+                                task_id: task_id(thread, task),
+                                task_definition: TaskDefinition {
+                                    kind: "shell_command".to_string(),
+                                    parameters: json!({"program": "true"}),
+                                    ..Default::default()
+                                },
+                            })
+                            .unwrap();
+                    }
+                    let mut claimed = Vec::new();
+                    for other_thread in 0..4 {
+                        for task in 0..10 {
+                            let task_id = task_id(other_thread, task);
+                            match registry.claim_task(&task_id) {
+                                Ok(true) => claimed.push(task_id),
+                                Ok(false) | Err(RegistryError::NotFound { .. }) => {}
+                                Err(error) => panic!("could not claim {task_id}: {error}"),
+                            }
+                        }
+                    }
+                    claimed
+                })
+            })
+            .collect();
+        let mut claimed: Vec<String> = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+        // Each exactly once
+        assert_eq!(claimed.len(), 40);
+        claimed.sort();
+        claimed.dedup();
+        assert_eq!(claimed.len(), 40);
+        for task_id in claimed {
+            let task_state = registry.get_task(&task_id).unwrap();
+            assert_eq!(task_state.status, TaskStatus::QUEUED);
+            assert_eq!(task_state.attempt, 1);
+            assert_eq!(registry.get_task_events(&task_id).unwrap().len(), 2);
+        }
+        drop(registry);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,399 @@
+use crate::core::core_types::timestamp_now;
+use crate::core::retry_policy::RetryPolicy;
+use crate::registry::task_registry::RegistryError;
+
+/// Brings the tables of one registry from one schema version to the next.
+///
+/// Databases written before the schema was versioned are at version 0 whatever
+/// columns they already have, so migrations must cope with finding their
+/// changes partly applied.
+type Migration = fn(&sqlite::Connection, &str) -> Result<(), RegistryError>;
+
+/// In order, the version of a database is the number of these that have run on
+/// it. Append only: never edit or reorder a migration once released.
+const MIGRATIONS: [Migration; 16] = [
+    create_tasks_table,
+    add_task_kinds,
+    add_task_output,
+    create_dependencies_table,
+    add_retries,
+    add_timeouts,
+    add_orphan_policy,
+    add_idempotency_key,
+    add_timestamps,
+    create_events_table,
+    add_task_error,
+    add_priority,
+    add_queue,
+    add_run_at,
+    create_schedules_table,
+    add_page_indexes,
+];
+
+/// The schema version this build reads and writes.
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Runs whichever migrations the tables named after `table_name` are missing
+/// and returns the resulting schema version. Refuses databases written by a
+/// newer version, rather than risk corrupting them.
+pub fn migrate(connection: &sqlite::Connection, table_name: &str) -> Result<usize, RegistryError> {
+    connection.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {table_name}_schema_version (version INTEGER NOT NULL);"
+    ))?;
+    let version = get_version(connection, table_name)?;
+    if version == SCHEMA_VERSION {
+        return Ok(version);
+    }
+    check_version(version)?;
+    loop {
+        // Each migration runs in its own transaction along with the version
+        // bump, and the version is read again inside it, so concurrent
+        // processes starting up do not run the same migration twice.
+        connection.execute("BEGIN IMMEDIATE")?;
+        let result = get_version(connection, table_name).and_then(|version| {
+            check_version(version)?;
+            if version == SCHEMA_VERSION {
+                return Ok(None);
+            }
+            println!("Migrating {table_name} to schema version {}", version + 1);
+            MIGRATIONS[version](connection, table_name)?;
+            set_version(connection, table_name, version + 1)?;
+            Ok(Some(version + 1))
+        });
+        match result {
+            Ok(Some(_)) => connection.execute("COMMIT")?,
+            Ok(None) => {
+                connection.execute("COMMIT")?;
+                return Ok(SCHEMA_VERSION);
+            }
+            Err(error) => {
+                if let Err(rollback_error) = connection.execute("ROLLBACK") {
+                    println!("Could not roll back migration: {rollback_error}");
+                }
+                return Err(error);
+            }
+        }
+    }
+}
+
+fn check_version(version: usize) -> Result<(), RegistryError> {
+    if version > SCHEMA_VERSION {
+        return Err(RegistryError::UnsupportedSchema {
+            version,
+            supported: SCHEMA_VERSION,
+        });
+    }
+    Ok(())
+}
+
+fn get_version(connection: &sqlite::Connection, table_name: &str) -> Result<usize, RegistryError> {
+    let query = format!("SELECT version FROM {table_name}_schema_version");
+    let mut statement = connection.prepare(query)?;
+    match statement.iter().next() {
+        Some(row_result) => match row_result?.read::<Option<i64>, _>(0) {
+            Some(version) if version >= 0 => Ok(version as usize),
+            version => Err(RegistryError::CorruptRow {
+                message: format!("invalid schema version {version:?}"),
+            }),
+        },
+        None => Ok(0),
+    }
+}
+
+fn set_version(
+    connection: &sqlite::Connection,
+    table_name: &str,
+    version: usize,
+) -> Result<(), RegistryError> {
+    connection.execute(format!("DELETE FROM {table_name}_schema_version"))?;
+    connection.execute(format!(
+        "INSERT INTO {table_name}_schema_version (version) VALUES ({version})"
+    ))?;
+    Ok(())
+}
+
+fn has_column(
+    connection: &sqlite::Connection,
+    table_name: &str,
+    column: &str,
+) -> Result<bool, RegistryError> {
+    let mut statement = connection.prepare(format!("PRAGMA table_info({table_name})"))?;
+    for row_result in statement.iter() {
+        if row_result?.read::<&str, _>("name") == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// `columns` are `name TYPE` pairs.
+fn add_columns(
+    connection: &sqlite::Connection,
+    table_name: &str,
+    columns: &[&str],
+) -> Result<(), RegistryError> {
+    for column in columns {
+        let name = column.split_whitespace().next().unwrap();
+        if !has_column(connection, table_name, name)? {
+            connection.execute(format!("ALTER TABLE {table_name} ADD COLUMN {column}"))?;
+        }
+    }
+    Ok(())
+}
+
+fn create_tasks_table(
+    connection: &sqlite::Connection,
+    table_name: &str,
+) -> Result<(), RegistryError> {
+    connection.execute(format!(
+        "CREATE TABLE IF NOT EXISTS {table_name} (status TEXT, name TEXT PRIMARY KEY);"
+    ))?;
+    Ok(())
+}
+
+/// Tasks used to be sleep and write tasks with their parameters in columns of
+/// their own. Those are moved into `parameters` and left otherwise unused.
+fn add_task_kinds(connection: &sqlite::Connection, table_name: &str) -> Result<(), RegistryError> {
+    add_columns(connection, table_name, &["kind TEXT", "parameters TEXT"])?;
+    if !has_column(connection, table_name, "sleep_time_seconds")? {
+        return Ok(());
+    }
+    let query = format!(
+        "SELECT name, sleep_time_seconds, message, output_path FROM {table_name} WHERE kind IS NULL"
+    );
+    let mut legacy_tasks = Vec::new();
+    for row_result in connection.prepare(query)?.iter() {
+        let row = row_result?;
+        let parameters = serde_json::json!({
+            "sleep_time_seconds": row.read::<Option<i64>, _>(1),
+            "message": row.read::<Option<&str>, _>(2),
+            "output_path": row.read::<Option<&str>, _>(3),
+        });
+        legacy_tasks.push((row.read::<&str, _>(0).to_string(), parameters.to_string()));
+    }
+    let query = format!(
+        "UPDATE {table_name} SET kind = :kind, parameters = :parameters WHERE name = :name"
+    );
+    for (name, parameters) in legacy_tasks {
+        let mut statement = connection.prepare(&query)?;
+        statement.bind_iter::<_, (_, sqlite::Value)>([
+            (":kind", "sleep_and_write".into()),
+            (":parameters", parameters.into()),
+            (":name", name.into()),
+        ])?;
+        statement.next()?;
+    }
+    Ok(())
+}
+
+fn add_task_output(connection: &sqlite::Connection, table_name: &str) -> Result<(), RegistryError> {
+    add_columns(
+        connection,
+        table_name,
+        &["exit_code INTEGER", "stdout TEXT", "stderr TEXT"],
+    )
+}
+
+fn create_dependencies_table(
+    connection: &sqlite::Connection,
+    table_name: &str,
+) -> Result<(), RegistryError> {
+    connection.execute(format!("CREATE TABLE IF NOT EXISTS {table_name}_dependencies (name TEXT, depends_on TEXT, PRIMARY KEY (name, depends_on));"))?;
+    Ok(())
+}
+
+/// Existing tasks get the default retry policy, of a single attempt.
+fn add_retries(connection: &sqlite::Connection, table_name: &str) -> Result<(), RegistryError> {
+    add_columns(
+        connection,
+        table_name,
+        &[
+            "retry_policy TEXT",
+            "attempt INTEGER",
+            "next_attempt_at INTEGER",
+        ],
+    )?;
+    connection.execute(format!("CREATE TABLE IF NOT EXISTS {table_name}_attempt_errors (name TEXT, attempt INTEGER, kind TEXT, message TEXT);"))?;
+    let mut statement = connection.prepare(format!(
+        "UPDATE {table_name} SET retry_policy = :retry_policy WHERE retry_policy IS NULL"
+    ))?;
+    statement.bind((
+        ":retry_policy",
+        serde_json::to_string(&RetryPolicy::default())
+            .unwrap()
+            .as_str(),
+    ))?;
+    statement.next()?;
+    connection.execute(format!(
+        "UPDATE {table_name} SET attempt = 0 WHERE attempt IS NULL"
+    ))?;
+    Ok(())
+}
+
+fn add_timeouts(connection: &sqlite::Connection, table_name: &str) -> Result<(), RegistryError> {
+    add_columns(connection, table_name, &["timeout_seconds REAL"])
+}
+
+fn add_orphan_policy(
+    connection: &sqlite::Connection,
+    table_name: &str,
+) -> Result<(), RegistryError> {
+    add_columns(connection, table_name, &["on_orphaned TEXT"])
+}
+
+fn add_idempotency_key(
+    connection: &sqlite::Connection,
+    table_name: &str,
+) -> Result<(), RegistryError> {
+    add_columns(connection, table_name, &["idempotency_key TEXT"])
+}
+
+/// When existing tasks were created is not known, they are taken to have
+/// been created by the migration.
+fn add_timestamps(connection: &sqlite::Connection, table_name: &str) -> Result<(), RegistryError> {
+    add_columns(
+        connection,
+        table_name,
+        &[
+            "created_at INTEGER",
+            "queued_at INTEGER",
+            "started_at INTEGER",
+            "finished_at INTEGER",
+        ],
+    )?;
+    let mut statement = connection.prepare(format!(
+        "UPDATE {table_name} SET created_at = :now WHERE created_at IS NULL"
+    ))?;
+    statement.bind((":now", timestamp_now().timestamp_millis()))?;
+    statement.next()?;
+    Ok(())
+}
+
+fn create_events_table(
+    connection: &sqlite::Connection,
+    table_name: &str,
+) -> Result<(), RegistryError> {
+    connection.execute(format!("CREATE TABLE IF NOT EXISTS {table_name}_events (name TEXT, at INTEGER, kind TEXT, status TEXT, message TEXT);"))?;
+    Ok(())
+}
+
+fn add_task_error(connection: &sqlite::Connection, table_name: &str) -> Result<(), RegistryError> {
+    add_columns(connection, table_name, &["error TEXT"])
+}
+
+/// Existing tasks get the default priority.
+fn add_priority(connection: &sqlite::Connection, table_name: &str) -> Result<(), RegistryError> {
+    add_columns(connection, table_name, &["priority INTEGER"])?;
+    connection.execute(format!(
+        "UPDATE {table_name} SET priority = 0 WHERE priority IS NULL"
+    ))?;
+    Ok(())
+}
+
+fn add_queue(connection: &sqlite::Connection, table_name: &str) -> Result<(), RegistryError> {
+    add_columns(connection, table_name, &["queue TEXT"])
+}
+
+/// Indexed, since the control loop looks for due tasks on every tick.
+fn add_run_at(connection: &sqlite::Connection, table_name: &str) -> Result<(), RegistryError> {
+    add_columns(connection, table_name, &["run_at INTEGER"])?;
+    connection.execute(format!(
+        "CREATE INDEX IF NOT EXISTS {table_name}_status_run_at ON {table_name} (status, run_at);"
+    ))?;
+    Ok(())
+}
+
+fn create_schedules_table(
+    connection: &sqlite::Connection,
+    table_name: &str,
+) -> Result<(), RegistryError> {
+    connection.execute(format!("CREATE TABLE IF NOT EXISTS {table_name}_schedules (id TEXT PRIMARY KEY, cron TEXT, timezone TEXT, task_definition TEXT, missed_runs TEXT, overlap TEXT, created_at INTEGER, last_run_at INTEGER, last_task_id TEXT);"))?;
+    Ok(())
+}
+
+/// Pages are ordered by `created_at` and load the attempt errors of all their
+/// tasks at once.
+fn add_page_indexes(
+    connection: &sqlite::Connection,
+    table_name: &str,
+) -> Result<(), RegistryError> {
+    connection.execute(format!(
+        "CREATE INDEX IF NOT EXISTS {table_name}_created_at ON {table_name} (created_at);"
+    ))?;
+    connection.execute(format!(
+        "CREATE INDEX IF NOT EXISTS {table_name}_attempt_errors_name ON {table_name}_attempt_errors (name);"
+    ))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::core_types::TaskStatus;
+    use crate::registry::task_registry::{RegistryError, TaskRegistry};
+    use crate::registry::task_registry_sqlite::{TablePermanance, TaskRegistrySqlite};
+    use crate::registry::task_registry_sqlite_migrations::{migrate, SCHEMA_VERSION};
+    use serde_json::json;
+
+    fn database_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("task_registry_migrations_{name}.db"));
+        // Left over from an earlier run
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn migrate_unversioned_database() {
+        let database = database_path("unversioned");
+        {
+            // As written before task kinds, schema versions or anything else
+            let connection = sqlite::Connection::open(&database).unwrap();
+            connection
+                .execute("CREATE TABLE tasks (status TEXT, name TEXT PRIMARY KEY, sleep_time_seconds INTEGER, message TEXT, output_path TEXT);")
+                .unwrap();
+            connection
+                .execute("INSERT INTO tasks VALUES ('SUCCESS', 'old task', 3, 'hello', '/tmp/hello.txt');")
+                .unwrap();
+        }
+
+        assert_eq!(
+            TaskRegistrySqlite::migrate(&database, "tasks").unwrap(),
+            SCHEMA_VERSION
+        );
+        // Migrating again changes nothing
+        assert_eq!(
+            TaskRegistrySqlite::migrate(&database, "tasks").unwrap(),
+            SCHEMA_VERSION
+        );
+        let registry = TaskRegistrySqlite::new(&database, "tasks", TablePermanance::Keep).unwrap();
+        let task_state = registry.get_task("old task").unwrap();
+        assert_eq!(task_state.status, TaskStatus::SUCCESS);
+        assert_eq!(task_state.kind, "sleep_and_write");
+        assert_eq!(
+            task_state.parameters,
+            json!({"sleep_time_seconds": 3, "message": "hello", "output_path": "/tmp/hello.txt"})
+        );
+        assert_eq!(task_state.attempt, 0);
+        assert_eq!(task_state.error, None);
+        assert_eq!(task_state.priority, 0);
+        std::fs::remove_file(&database).unwrap();
+    }
+
+    #[test]
+    fn refuse_newer_database() {
+        let connection = sqlite::Connection::open(":memory:").unwrap();
+        assert_eq!(migrate(&connection, "tasks").unwrap(), SCHEMA_VERSION);
+        connection
+            .execute(format!(
+                "UPDATE tasks_schema_version SET version = {}",
+                SCHEMA_VERSION + 1
+            ))
+            .unwrap();
+        assert_eq!(
+            migrate(&connection, "tasks"),
+            Err(RegistryError::UnsupportedSchema {
+                version: SCHEMA_VERSION + 1,
+                supported: SCHEMA_VERSION,
+            })
+        );
+    }
+}
@@ -1,74 +1,455 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    cmp::Ordering as CmpOrdering,
+    collections::BinaryHeap,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        mpsc, Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+/// How long a job waits to gain one level of priority, so that a steady
+/// stream of urgent jobs cannot starve the rest.
+pub const DEFAULT_AGING_INTERVAL: Duration = Duration::from_secs(10);
+
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    shared: Arc<Shared>,
+    aging_interval: Duration,
+    /// Workers not yet asked to retire
+    size: usize,
+    next_id: usize,
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+/// Returns whether the worker running it was replaced while it ran, see
+/// `ThreadPool::abandon`, and so should stop.
+type Job = Box<dyn FnOnce() -> bool + Send + 'static>;
 
-impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+/// A job waiting for a worker, ordered so that the greatest runs next.
+struct QueuedJob {
+    /// The priority, in milliseconds of aging, less when the job was queued.
+    /// A job queued one aging interval earlier ranks as one priority higher.
+    rank: i64,
+    /// Breaks ties first come, first served
+    sequence: u64,
+    job: Job,
+}
 
-        let (sender, receiver) = mpsc::channel();
+impl PartialEq for QueuedJob {
+    fn eq(&self, other: &QueuedJob) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
 
-        let receiver = Arc::new(Mutex::new(receiver));
+impl Eq for QueuedJob {}
 
-        let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
+impl PartialOrd for QueuedJob {
+    fn partial_cmp(&self, other: &QueuedJob) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
 
-        ThreadPool {
-            workers,
-            sender: Some(sender),
+impl Ord for QueuedJob {
+    fn cmp(&self, other: &QueuedJob) -> CmpOrdering {
+        self.rank
+            .cmp(&other.rank)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: BinaryHeap<QueuedJob>,
+    next_sequence: u64,
+    /// Workers to stop as soon as they are idle
+    retiring: usize,
+    /// Set when the pool is dropped. Workers still empty the queue first.
+    closed: bool,
+}
+
+/// What workers pick up, instead of reading from the queue directly.
+enum Message {
+    NewJob(Job),
+    Retire,
+    Disconnected,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Signalled whenever there is something in `queue` for a worker to do
+    available: Condvar,
+    /// Origin of `QueuedJob::rank`
+    created_at: Instant,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Blocks until there is a job, a retirement or a shutdown to act on.
+    fn next_message(&self) -> Message {
+        let mut queue = self.lock();
+        loop {
+            if queue.retiring > 0 {
+                queue.retiring -= 1;
+                return Message::Retire;
+            }
+            if let Some(queued_job) = queue.jobs.pop() {
+                return Message::NewJob(queued_job.job);
+            }
+            if queue.closed {
+                return Message::Disconnected;
+            }
+            queue = self
+                .available
+                .wait(queue)
+                .unwrap_or_else(PoisonError::into_inner);
         }
     }
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::with_aging_interval(size, DEFAULT_AGING_INTERVAL)
+    }
+
+    /// A pool where waiting jobs gain one level of priority every
+    /// `aging_interval`.
+    pub fn with_aging_interval(size: usize, aging_interval: Duration) -> ThreadPool {
+        assert!(size > 0);
+        assert!(aging_interval.as_millis() > 0);
+
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            available: Condvar::new(),
+            created_at: Instant::now(),
+        });
+
+        let mut pool = ThreadPool {
+            workers: Vec::with_capacity(size),
+            shared,
+            aging_interval,
+            size: 0,
+            next_id: 0,
+        };
+        pool.resize(size);
+        pool
+    }
 
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        self.execute_with_priority(0, f);
+    }
+
+    /// Queues `f` to run before jobs of a lower priority, once aging is
+    /// taken into account.
+    pub fn execute_with_priority<F>(&self, priority: i32, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.queue_job(
+            priority,
+            Box::new(move || {
+                f();
+                false
+            }),
+        );
+    }
+
+    fn queue_job(&self, priority: i32, job: Job) {
+        let queued_at = self.shared.created_at.elapsed().as_millis() as i64;
+        let rank = priority as i64 * self.aging_interval.as_millis() as i64 - queued_at;
+        let mut queue = self.shared.lock();
+        let sequence = queue.next_sequence;
+        queue.next_sequence += 1;
+        queue.jobs.push(QueuedJob {
+            rank,
+            sequence,
+            job,
+        });
+        drop(queue);
+        self.shared.available.notify_one();
+    }
+
+    /// Like `execute`, but returns a handle to wait for what `f` returns. A
+    /// panic in `f` is handed to the handle rather than only logged.
+    pub fn submit<T, F>(&self, f: F) -> JobHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.submit_with_priority(0, f)
+    }
+
+    /// `submit` with the priority of `execute_with_priority`.
+    pub fn submit_with_priority<T, F>(&self, priority: i32, f: F) -> JobHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let state = Arc::new(JobState::default());
+        let job_state = Arc::clone(&state);
+        self.queue_job(
+            priority,
+            Box::new(move || {
+                if !job_state.start() {
+                    // Cancelled while queued, dropping the sender tells the handle
+                    return false;
+                }
+                let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+                    JobError::Panicked {
+                        message: panic_message(&*payload),
+                    }
+                });
+                // Nobody may be waiting any more
+                let _ = sender.send(result);
+                job_state.status.swap(FINISHED, Ordering::SeqCst) == ABANDONED
+            }),
+        );
+        JobHandle {
+            receiver,
+            state,
+            taken: false,
+        }
+    }
 
-        self.sender.as_ref().unwrap().send(job).unwrap();
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Gives up on a running job that will not stop, e.g. one that has timed
+    /// out without checking for it: a new worker takes over its slot, and the
+    /// worker stuck with it shuts down once it returns. Returns whether the
+    /// job was running, jobs that are queued or finished are left alone.
+    pub fn abandon<T>(&mut self, handle: &JobHandle<T>) -> bool {
+        let abandoned = handle
+            .state
+            .status
+            .compare_exchange(RUNNING, ABANDONED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+        if abandoned {
+            self.forget_finished_workers();
+            println!("Starting worker {} to replace a stuck one", self.next_id);
+            self.workers
+                .push(Worker::new(self.next_id, Arc::clone(&self.shared)));
+            self.next_id += 1;
+        }
+        abandoned
+    }
+
+    /// Joins workers that have retired or were replaced.
+    fn forget_finished_workers(&mut self) {
+        self.workers.retain_mut(|worker| {
+            match worker.thread.take_if(|thread| thread.is_finished()) {
+                Some(thread) => {
+                    if thread.join().is_err() {
+                        println!("Worker {} had panicked", worker.id);
+                    }
+                    false
+                }
+                None => true,
+            }
+        });
+    }
+
+    /// Starts workers or retires idle ones until there are `size`. A worker
+    /// that is busy when asked to retire finishes its job first.
+    pub fn resize(&mut self, size: usize) {
+        assert!(size > 0);
+
+        self.forget_finished_workers();
+        let mut queue = self.shared.lock();
+        while self.size < size {
+            // Workers that have not retired yet may as well stay
+            if queue.retiring > 0 {
+                queue.retiring -= 1;
+            } else {
+                self.workers
+                    .push(Worker::new(self.next_id, Arc::clone(&self.shared)));
+                self.next_id += 1;
+            }
+            self.size += 1;
+        }
+        while self.size > size {
+            queue.retiring += 1;
+            self.size -= 1;
+        }
+        drop(queue);
+        self.shared.available.notify_all();
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        self.shared.lock().closed = true;
+        self.shared.available.notify_all();
         for worker in &mut self.workers {
             println!("Shutting down worker {}", worker.id);
 
             if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+                if thread.join().is_err() {
+                    println!("Worker {} had panicked", worker.id);
+                }
             }
         }
     }
 }
 
+/// The message a panic was started with, e.g. by `panic!` or `unwrap`.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.to_string()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+const QUEUED: u8 = 0;
+const RUNNING: u8 = 1;
+const FINISHED: u8 = 2;
+const CANCELLED: u8 = 3;
+/// Still running, on a worker that has been replaced
+const ABANDONED: u8 = 4;
+
+#[derive(Default)]
+struct JobState {
+    status: AtomicU8,
+    started_at: OnceLock<Instant>,
+}
+
+impl JobState {
+    /// Whether the job may run, i.e. it was not cancelled first.
+    fn start(&self) -> bool {
+        let started = self
+            .status
+            .compare_exchange(QUEUED, RUNNING, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+        if started {
+            let _ = self.started_at.set(Instant::now());
+        }
+        started
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobError {
+    /// The job was cancelled before a worker picked it up.
+    Cancelled,
+    Panicked {
+        message: String,
+    },
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Cancelled => write!(f, "job was cancelled"),
+            JobError::Panicked { message } => write!(f, "job panicked: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+/// Waits for, or checks on, a job passed to `ThreadPool::submit`. Dropping it
+/// does not stop the job.
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<Result<T, JobError>>,
+    state: Arc<JobState>,
+    /// Whether `try_result` has already handed the result out
+    taken: bool,
+}
+
+impl<T> JobHandle<T> {
+    /// Blocks until the job has finished. Panics if `try_result` already
+    /// handed the result out.
+    pub fn join(self) -> Result<T, JobError> {
+        if self.taken {
+            panic!("job result was already taken by try_result");
+        }
+        // The sender is only dropped without sending if the job never ran
+        self.receiver.recv().unwrap_or(Err(JobError::Cancelled))
+    }
+
+    /// The result, if the job has finished, without waiting for it. It is
+    /// handed out once, later calls return `None`.
+    pub fn try_result(&mut self) -> Option<Result<T, JobError>> {
+        if self.taken {
+            return None;
+        }
+        let result = match self.receiver.try_recv() {
+            Ok(result) => result,
+            Err(mpsc::TryRecvError::Empty) => return None,
+            Err(mpsc::TryRecvError::Disconnected) => Err(JobError::Cancelled),
+        };
+        self.taken = true;
+        Some(result)
+    }
+
+    /// Whether the job has finished or was cancelled, so that `join` would
+    /// not block.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.state.status.load(Ordering::SeqCst),
+            FINISHED | CANCELLED
+        )
+    }
+
+    /// When a worker picked the job up, if one has.
+    pub fn started_at(&self) -> Option<Instant> {
+        self.state.started_at.get().copied()
+    }
+
+    /// Stops the job from running if no worker has picked it up yet, and
+    /// returns whether it did. A job already running is left to finish.
+    pub fn cancel(&self) -> bool {
+        self.state
+            .status
+            .compare_exchange(QUEUED, CANCELLED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+}
+
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(id: usize, shared: Arc<Shared>) -> Worker {
         let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-
-            match message {
-                Ok(job) => {
+            match shared.next_message() {
+                Message::NewJob(job) => {
                     println!("Worker {id} got a job; executing.");
 
-                    job();
+                    // A panicking job must not take the worker, and so a slot
+                    // of the pool, down with it
+                    match panic::catch_unwind(AssertUnwindSafe(job)) {
+                        Ok(false) => {}
+                        Ok(true) => {
+                            println!("Worker {id} was replaced; shutting down.");
+                            break;
+                        }
+                        Err(payload) => {
+                            println!("Worker {id} job panicked: {}", panic_message(&*payload));
+                        }
+                    }
+                }
+                Message::Retire => {
+                    println!("Worker {id} retired; shutting down.");
+                    break;
                 }
-                Err(_) => {
+                Message::Disconnected => {
                     println!("Worker {id} disconnected; shutting down.");
                     break;
                 }
@@ -80,3 +461,190 @@ impl Worker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::threadpool::threadpool::{panic_message, JobError, ThreadPool};
+
+    /// Runs `jobs` jobs that each take 200ms and returns how long they took.
+    fn time_jobs(pool: ThreadPool, jobs: usize) -> Duration {
+        let started = Instant::now();
+        for _ in 0..jobs {
+            pool.execute(|| thread::sleep(Duration::from_millis(200)));
+        }
+        // Waits for the queue to drain
+        drop(pool);
+        started.elapsed()
+    }
+
+    #[test]
+    fn grow_runs_more_jobs_at_once() {
+        let mut pool = ThreadPool::new(1);
+        pool.resize(4);
+        assert_eq!(pool.size(), 4);
+        assert!(time_jobs(pool, 4) < Duration::from_millis(600));
+    }
+
+    #[test]
+    fn shrink_runs_fewer_jobs_at_once() {
+        let mut pool = ThreadPool::new(4);
+        pool.resize(1);
+        assert_eq!(pool.size(), 1);
+        assert!(time_jobs(pool, 3) >= Duration::from_millis(600));
+    }
+
+    #[test]
+    fn panicking_jobs_keep_the_worker() {
+        let pool = ThreadPool::new(1);
+        let done = Arc::new(AtomicUsize::new(0));
+        for i in 0..3 {
+            let done = done.clone();
+            pool.execute(move || {
+                if i == 1 {
+                    panic!("job {i} exploded");
+                }
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn panic_messages() {
+        let payload = std::panic::catch_unwind(|| panic!("static")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "static");
+        let payload = std::panic::catch_unwind(|| panic!("formatted {}", 1)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "formatted 1");
+    }
+
+    #[test]
+    fn resize_keeps_queued_jobs() {
+        let mut pool = ThreadPool::new(2);
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..20 {
+            let done = done.clone();
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(10));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        pool.resize(1);
+        pool.resize(3);
+        pool.resize(1);
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    fn submitted_jobs_return_results() {
+        let pool = ThreadPool::new(2);
+        let handles: Vec<_> = (0..4).map(|i| pool.submit(move || i * 10)).collect();
+        let results: Vec<_> = handles.into_iter().map(|x| x.join().unwrap()).collect();
+        assert_eq!(results, vec![0, 10, 20, 30]);
+
+        let handle = pool.submit(|| -> u32 { panic!("job exploded") });
+        assert_eq!(
+            handle.join(),
+            Err(JobError::Panicked {
+                message: "job exploded".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn try_result_does_not_wait() {
+        let pool = ThreadPool::new(1);
+        let mut handle = pool.submit(|| {
+            thread::sleep(Duration::from_millis(200));
+            "done"
+        });
+        assert_eq!(handle.try_result(), None);
+        assert!(!handle.is_finished());
+        while !handle.is_finished() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(handle.started_at().is_some());
+        assert_eq!(handle.try_result(), Some(Ok("done")));
+        // Only handed out once
+        assert_eq!(handle.try_result(), None);
+    }
+
+    #[test]
+    fn cancel_queued_jobs() {
+        let pool = ThreadPool::new(1);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let busy = pool.submit(|| thread::sleep(Duration::from_millis(200)));
+        let queued = {
+            let ran = ran.clone();
+            pool.submit(move || ran.fetch_add(1, Ordering::SeqCst))
+        };
+        assert!(queued.cancel());
+        assert!(queued.is_finished());
+        assert_eq!(queued.started_at(), None);
+        assert_eq!(queued.join(), Err(JobError::Cancelled));
+        // Too late for a running job
+        while busy.started_at().is_none() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!busy.cancel());
+        assert_eq!(busy.join(), Ok(()));
+        drop(pool);
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn abandoned_jobs_give_up_their_worker() {
+        let mut pool = ThreadPool::new(1);
+        let stuck = pool.submit(|| thread::sleep(Duration::from_millis(500)));
+        let queued = pool.submit(|| ());
+        // Only a running job can be abandoned
+        assert!(!pool.abandon(&queued));
+        while stuck.started_at().is_none() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(pool.abandon(&stuck));
+        assert!(!pool.abandon(&stuck));
+
+        // Runs on the replacement while the stuck job still sleeps
+        assert_eq!(queued.join(), Ok(()));
+        assert!(!stuck.is_finished());
+        assert_eq!(stuck.join(), Ok(()));
+        assert_eq!(pool.size(), 1);
+        // The replaced worker has shut down, so one job runs at a time
+        assert!(time_jobs(pool, 2) >= Duration::from_millis(400));
+    }
+
+    /// Queues `priorities` behind a job that keeps the only worker busy and
+    /// returns the order they ran in, sleeping `between` after each.
+    fn run_order(pool: ThreadPool, priorities: &[i32], between: Duration) -> Vec<i32> {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        pool.execute(|| thread::sleep(Duration::from_millis(100)));
+        for &priority in priorities {
+            let order = order.clone();
+            pool.execute_with_priority(priority, move || order.lock().unwrap().push(priority));
+            thread::sleep(between);
+        }
+        drop(pool);
+        Arc::try_unwrap(order).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn higher_priorities_run_first() {
+        let order = run_order(ThreadPool::new(1), &[0, 5, -1, 1, 5], Duration::ZERO);
+        assert_eq!(order, vec![5, 5, 1, 0, -1]);
+    }
+
+    #[test]
+    fn waiting_jobs_age() {
+        // Queued three aging intervals earlier, the first job overtakes
+        let pool = ThreadPool::with_aging_interval(1, Duration::from_millis(20));
+        let order = run_order(pool, &[0, 2], Duration::from_millis(60));
+        assert_eq!(order, vec![0, 2]);
+    }
+}